@@ -14,11 +14,16 @@ func _enter_tree():
 	ImageHolder.connect("image_loaded",       ImageProcessor, "_on_imageholder_image_loaded")
 	ImageHolder.connect("image_loaded",       $UI, "_on_ImageHolder_image_loaded")
 	ImageHolder.connect("image_load_failure", $UI, "_on_ImageHolder_error_occured")
+	ImageHolder.connect("frames_normalized",  $UI, "_on_ImageHolder_frames_normalized")
+	ImageHolder.connect("companion_frames_loaded", ImageProcessor, "_on_imageholder_companion_frames_loaded")
+	ImageHolder.connect("region_masks_loaded", ImageProcessor, "_on_imageholder_region_masks_loaded")
 	
 	ImageProcessor.connect("image_processed", ImageHolder, "_on_imageprocessor_image_processed")
 	ImageProcessor.connect("error_occured",   $UI,         "_on_ImageProcessor_error_occured")
 	ImageProcessor.connect("image_processed", $UI,         "_on_ImageProcessor_image_processed")
 	ImageProcessor.connect("made_progress",   $UI,         "_on_ImageProcessor_made_progress")
+	ImageProcessor.connect("quality_evaluated", $UI,       "_on_ImageProcessor_quality_evaluated")
+	ImageProcessor.connect("autotune_finished", $UI,       "_on_ImageProcessor_autotune_finished")
 	
 	ImageSaver.connect("image_save_failure", $UI, "_on_ImageSaver_image_save_failure")
 	ImageSaver.connect("image_save_success", $UI, "_on_ImageSaver_image_save_success")
@@ -26,7 +31,18 @@ func _enter_tree():
 	$UI.connect("loaded_gif",               ImageHolder,    "_on_ui_loaded_gif")
 	$UI.connect("loaded_separate_frames",   ImageHolder,    "_on_ui_loaded_separate_frames")
 	$UI.connect("loaded_spritesheet",       ImageHolder,    "_on_ui_loaded_spritesheet")
+	$UI.connect("loaded_atlas",             ImageHolder,    "_on_ui_loaded_atlas")
+	$UI.connect("loaded_flow_fields",       ImageProcessor, "_on_ui_loaded_flow_fields")
+	$UI.connect("loaded_companion_frames",  ImageHolder,    "_on_ui_loaded_companion_frames")
+	$UI.connect("loaded_region_masks",      ImageHolder,    "_on_ui_loaded_region_masks")
 	$UI.connect("img_params_changed",       ImageProcessor, "_on_ui_img_params_changed")
+	$UI.connect("requested_quality_report", ImageProcessor, "_on_ui_requested_quality_report")
+	$UI.connect("requested_autotune",       ImageProcessor, "_on_ui_requested_autotune")
+	$UI.connect("cancelled_autotune",       ImageProcessor, "_on_ui_cancelled_autotune")
 	$UI.connect("exported_gif",             ImageSaver,     "_on_ui_exported_gif")
 	$UI.connect("exported_separate_frames", ImageSaver,     "_on_ui_exported_separate_frames")
 	$UI.connect("exported_spritesheet",     ImageSaver,     "_on_ui_exported_spritesheet")
+	$UI.connect("exported_aseprite",        ImageSaver,     "_on_ui_exported_aseprite")
+	$UI.connect("exported_spriteframes",    ImageSaver,     "_on_ui_exported_spriteframes")
+	$UI.connect("exported_flowmap",         ImageSaver,     "_on_ui_exported_flowmap")
+	$UI.connect("exported_flow_fields",     ImageSaver,     "_on_ui_exported_flow_fields")
@@ -0,0 +1,142 @@
+extends ConfirmationDialog
+
+# Lets the user place matching points on two consecutive frames.
+# Left click on frame A starts a point, left click on frame B finishes it. Right click removes the nearest point.
+
+signal correspondences_confirmed(correspondences)
+
+const POINT_RADIUS = 4.0
+const COLORS = [Color.red, Color.green, Color.dodgerblue, Color.yellow, Color.magenta, Color.cyan, Color.orange]
+
+var input_frames = []
+var loop_seamlessly = true
+# One array of {"from": [x, y], "to": [x, y]} per frame pair
+var correspondences = []
+
+var pair_index = 0
+var pending_from = null
+
+var pair_spinbox = SpinBox.new()
+var texrect_a = TextureRect.new()
+var texrect_b = TextureRect.new()
+var hint = Label.new()
+
+func _ready():
+	window_title = "Correspondence points"
+	resizable = true
+
+	var pair_count = len(input_frames) if loop_seamlessly else len(input_frames) - 1
+	while len(correspondences) < pair_count:
+		correspondences.append([])
+
+	var vbox = VBoxContainer.new()
+	add_child(vbox)
+
+	var hbox_top = HBoxContainer.new()
+	vbox.add_child(hbox_top)
+	var label = Label.new()
+	label.text = "Frame pair"
+	hbox_top.add_child(label)
+	pair_spinbox.min_value = 1
+	pair_spinbox.max_value = max(pair_count, 1)
+	pair_spinbox.connect("value_changed", self, "_on_pair_changed")
+	hbox_top.add_child(pair_spinbox)
+	hbox_top.add_child(hint)
+
+	var hbox = HBoxContainer.new()
+	hbox.size_flags_vertical = SIZE_EXPAND_FILL
+	vbox.add_child(hbox)
+	for texrect in [texrect_a, texrect_b]:
+		texrect.expand = true
+		texrect.stretch_mode = TextureRect.STRETCH_KEEP_ASPECT_CENTERED
+		texrect.size_flags_horizontal = SIZE_EXPAND_FILL
+		texrect.size_flags_vertical = SIZE_EXPAND_FILL
+		texrect.mouse_filter = MOUSE_FILTER_STOP
+		texrect.connect("draw", self, "_on_texrect_draw", [texrect])
+		hbox.add_child(texrect)
+	texrect_a.connect("gui_input", self, "_on_texrect_a_gui_input")
+	texrect_b.connect("gui_input", self, "_on_texrect_b_gui_input")
+
+	connect("confirmed", self, "_on_confirmed")
+	connect("popup_hide", self, "queue_free")
+
+	_on_pair_changed(1)
+
+func _on_pair_changed(value):
+	pair_index = int(value) - 1
+	pending_from = null
+	texrect_a.texture = input_frames[pair_index]
+	texrect_b.texture = input_frames[(pair_index + 1) % len(input_frames)]
+	update_view()
+
+func update_view():
+	if pending_from == null:
+		hint.text = "Click a point on frame %d" % (pair_index + 1)
+	else:
+		hint.text = "Click where it ends up on frame %d" % ((pair_index + 1) % len(input_frames) + 1)
+	texrect_a.update()
+	texrect_b.update()
+
+# Where the texture is drawn inside the TextureRect, as [offset, scale]
+func get_texture_transform(texrect):
+	var tex_size = texrect.texture.get_size()
+	var scale = min(texrect.rect_size.x / tex_size.x, texrect.rect_size.y / tex_size.y)
+	var offset = (texrect.rect_size - tex_size * scale) / 2
+	return [offset, scale]
+
+func to_pixel(texrect, position):
+	var transform = get_texture_transform(texrect)
+	return (position - transform[0]) / transform[1]
+
+func to_position(texrect, pixel):
+	var transform = get_texture_transform(texrect)
+	return Vector2(pixel[0], pixel[1]) * transform[1] + transform[0]
+
+func _on_texrect_a_gui_input(event):
+	if event is InputEventMouseButton and event.pressed:
+		if event.button_index == BUTTON_LEFT:
+			var pixel = to_pixel(texrect_a, event.position)
+			pending_from = [pixel.x, pixel.y]
+			update_view()
+		elif event.button_index == BUTTON_RIGHT:
+			remove_nearest(texrect_a, event.position, "from")
+
+func _on_texrect_b_gui_input(event):
+	if event is InputEventMouseButton and event.pressed:
+		if event.button_index == BUTTON_LEFT and pending_from != null:
+			var pixel = to_pixel(texrect_b, event.position)
+			correspondences[pair_index].append({"from": pending_from, "to": [pixel.x, pixel.y]})
+			pending_from = null
+			update_view()
+		elif event.button_index == BUTTON_RIGHT:
+			remove_nearest(texrect_b, event.position, "to")
+
+func remove_nearest(texrect, position, key):
+	var points = correspondences[pair_index]
+	var nearest = -1
+	var nearest_distance = INF
+	for i in len(points):
+		var distance = to_position(texrect, points[i][key]).distance_to(position)
+		if distance < nearest_distance:
+			nearest = i
+			nearest_distance = distance
+
+	if nearest != -1:
+		points.remove(nearest)
+	update_view()
+
+func _on_texrect_draw(texrect):
+	if texrect.texture == null:
+		return
+
+	var key = "from" if texrect == texrect_a else "to"
+	var points = correspondences[pair_index]
+	for i in len(points):
+		var color = COLORS[i % len(COLORS)]
+		texrect.draw_circle(to_position(texrect, points[i][key]), POINT_RADIUS, color)
+
+	if pending_from != null and texrect == texrect_a:
+		texrect.draw_arc(to_position(texrect, pending_from), POINT_RADIUS * 2, 0, TAU, 16, Color.white)
+
+func _on_confirmed():
+	emit_signal("correspondences_confirmed", correspondences)
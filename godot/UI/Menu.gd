@@ -10,19 +10,55 @@ func file_button_pressed(id):
 		1: emit_signal("menu_item_clicked", "load_gif")
 		2: emit_signal("menu_item_clicked", "load_spritesheet")
 		3: emit_signal("menu_item_clicked", "load_separate_frames")		
+		13: emit_signal("menu_item_clicked", "load_flow_fields")
+		15: emit_signal("menu_item_clicked", "load_companion_frames")
+		28: emit_signal("menu_item_clicked", "load_region_masks")
+		
+		21, 22, 23, 24, 25, 26: set_frame_size_mode(id)
 		
 		5: emit_signal("menu_item_clicked", "export_gif")
 		6: emit_signal("menu_item_clicked", "export_spritesheet")
 		7: emit_signal("menu_item_clicked", "export_separate_frames")		
+		10: emit_signal("menu_item_clicked", "export_aseprite")
+		11: emit_signal("menu_item_clicked", "export_spriteframes")
+		12: emit_signal("menu_item_clicked", "export_flowmap")
+		14: emit_signal("menu_item_clicked", "export_flow_fields")
+		
+		17: emit_signal("menu_item_clicked", "evaluate_quality")
+		18: emit_signal("menu_item_clicked", "autotune")
+		19: emit_signal("menu_item_clicked", "cancel_autotune")
+		27: emit_signal("menu_item_clicked", "edit_correspondences")
 		
 		9: get_tree().quit()
 		
+# Menu id -> how ImageHolder normalises frames of different sizes
+const FRAME_SIZE_MODES = {
+	21: {"mode": "PadToLargest", "anchor": "Center"},
+	22: {"mode": "PadToLargest", "anchor": "BottomCenter"},
+	23: {"mode": "PadToLargest", "anchor": "TopLeft"},
+	24: {"mode": "CropToSmallest", "anchor": "Center"},
+	25: {"mode": "Scale", "anchor": "Center"},
+	26: {"mode": "Reject", "anchor": "Center"},
+}
+
+func set_frame_size_mode(id):
+	var popup = $File.get_popup()
+	for mode_id in FRAME_SIZE_MODES:
+		popup.set_item_checked(popup.get_item_index(mode_id), mode_id == id)
+	
+	ImageHolder.frame_size_params = FRAME_SIZE_MODES[id]
+		
 func _process(_delta):
 	
 	var disabled = !ImageHolder.has_output_frames() || ImageProcessor.is_busy()
 	
-	for i in [5, 6, 7]:
-		$File.get_popup().set_item_disabled(i, disabled)
+	var popup = $File.get_popup()
+	for id in [5, 6, 7, 10, 11, 12, 14]:
+		popup.set_item_disabled(popup.get_item_index(id), disabled)
+	
+	# Flow fields and companion frames belong to the loaded frames
+	for id in [13, 15, 17, 18, 27, 28]:
+		popup.set_item_disabled(popup.get_item_index(id), !ImageHolder.has_input_frames())
 
 func _on_About_pressed():
 	emit_signal("menu_item_clicked", "show_about")
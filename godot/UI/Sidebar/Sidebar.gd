@@ -41,11 +41,72 @@ func _ready():
 			"suffix": "x",
 		},
 		
+		"tileable": {
+			"label": "Tileable",
+			"ui_type": "bool",
+			"default": false
+		},
+		
+		# Ignored when tileable, that always wraps
+		"edge_mode": {
+			"label": "Edges",
+			"ui_type": "enum",
+			"items": ["Clamp", "Transparent", "Mirror", "Wrap"],
+			"default": "Clamp"
+		},
+		
+		# Removes jitter between the input frames before the motion is estimated
+		"stabilization": {
+			"label": "Stabilize",
+			"ui_type": "enum",
+			"items": ["None", "AlphaCentroid", "PhaseCorrelation", "FeetBaseline"],
+			"default": "None"
+		},
+		
+		"reapply_offsets": {
+			"label": "Keep original jitter",
+			"ui_type": "bool",
+			"default": false
+		},
+		
 		"sep_adv": {
 			"label": "Advanced",
 			"ui_type": "header",
 		},
 		
+		# Fitted before the flow algorithm runs, for scrolling and panning scenes
+		"global_motion": {
+			"label": "Global motion",
+			"ui_type": "enum",
+			"items": ["None", "Translation", "Affine", "Homography"],
+			"default": "None"
+		},
+		
+		# Every separate sprite (connected opaque area) moves on its own
+		"segment_objects": {
+			"label": "Separate objects",
+			"ui_type": "bool",
+			"default": false
+		},
+		
+		# How the correspondence points from Tools > Edit correspondence points are used
+		"correspondence_mode": {
+			"label": "Correspondence points",
+			"ui_type": "enum",
+			"items": ["Correct", "Interpolate"],
+			"default": "Correct"
+		},
+		
+		# 0 means a quarter of the frame size
+		"correspondence_radius": {
+			"label": "Correspondence radius",
+			"ui_type": "float",
+			"min": 0.0,
+			"max": 500.0,
+			"default": 0,
+			"suffix": "px",
+		},
+		
 		"optflow_alg": {
 			"label": "Flow algorithm",
 			"ui_type": "enum",
@@ -122,6 +183,76 @@ func _ready():
 			"belongs_to": optflow_items[1]
 		},
 		
+		# Blends every flow with the flows before and after it, against flickering.
+		# This and curved motion don't apply to frames split into objects.
+		"temporal_smoothing": {
+			"label": "Temporal smoothing",
+			"ui_type": "float",
+			"min": 0.0,
+			"max": 1.0,
+			"default": 0,
+		},
+		
+		"quadratic_motion": {
+			"label": "Curved motion",
+			"ui_type": "bool",
+			"default": false
+		},
+		
+		# What to show where the motion reveals areas that were hidden in the earlier frame
+		"hole_fill": {
+			"label": "Fill revealed areas",
+			"ui_type": "enum",
+			"items": ["None", "FrameB", "Transparent", "Diffusion", "Patch"],
+			"default": "None"
+		},
+		
+		"sep_refinement": {
+			"label": "Flow clean-up",
+			"ui_type": "header",
+		},
+		
+		# 0 turns each of these off
+		"outlier_threshold": {
+			"label": "Outlier threshold",
+			"ui_type": "float",
+			"min": 0.0,
+			"max": 50.0,
+			"default": 0,
+			"suffix": "px",
+		},
+		
+		"median_radius": {
+			"label": "Median filter radius",
+			"ui_type": "int",
+			"min": 0,
+			"max": 5,
+			"default": 0
+		},
+		
+		"edge_aware_filter": {
+			"label": "Edge-aware smoothing",
+			"ui_type": "enum",
+			"items": ["None", "JointBilateral", "Guided"],
+			"default": "None"
+		},
+		
+		"edge_aware_radius": {
+			"label": "Smoothing radius",
+			"ui_type": "int",
+			"min": 0,
+			"max": 20,
+			"default": 0
+		},
+		
+		"edge_aware_color_sigma": {
+			"label": "Smoothing colour tolerance",
+			"ui_type": "float",
+			"min": 0.0,
+			"max": 255.0,
+			"default": 0
+		},
+		
 		"sep_advadv": {
 			"label": "Super Advanced",
 			"ui_type": "header",
@@ -132,6 +263,29 @@ func _ready():
 			"ui_type": "bool",
 			"default": false
 		},
+		
+		"motion_vector_mode": {
+			"label": "Motion vector style",
+			"ui_type": "enum",
+			"items": ["ColorWheel", "Arrows"],
+			"default": "ColorWheel"
+		},
+		
+		# 0 means the largest motion in the animation is shown at full brightness
+		"motion_vector_scale": {
+			"label": "Motion vector scale",
+			"ui_type": "float",
+			"min": 0.0,
+			"max": 100.0,
+			"default": 0,
+			"suffix": "px",
+		},
+		
+		"motion_vector_legend": {
+			"label": "Show colour wheel legend",
+			"ui_type": "bool",
+			"default": false
+		},
 	}
 	
 	for key in img_params_ui:
@@ -198,7 +352,8 @@ func update_enum_visibility(enum_key):
 		var key = child.key
 		var value = img_params_ui[key]
 		
-		if value.has("belongs_to"):
+		# Only touch entries that belong to one of this enum's items, there can be multiple enums
+		if value.has("belongs_to") and value["belongs_to"] in img_params_ui[enum_key]["items"]:
 			var belongs_to = value["belongs_to"]
 			
 			var is_visible = belongs_to == enum_kind
@@ -26,6 +26,10 @@ func setup_spritesheet_params_ui(spritesheet_params_ui):
 			"bool":
 				instance = preload("res://UI/Sidebar/Entries/Check.tscn").instance()
 				instance.default = value["default"]
+			"enum":
+				instance = preload("res://UI/Sidebar/Entries/Enum.tscn").instance()
+				instance.items = value["items"]
+				instance.default = value["default"]
 			"header":
 				instance = preload("res://UI/Sidebar/Entries/Header.tscn").instance()
 		
@@ -89,6 +89,14 @@ func _ready():
 			"max": 10000,
 			"default": 0
 		},
+		
+		"frame_extrude": {
+			"label": "Extrude",
+			"ui_type": "number",
+			"min": 0,
+			"max": 32,
+			"default": 0
+		},
 
 	}
 	
@@ -128,9 +136,12 @@ func update_rects():
 	
 	var calculated_rects_per_row = tex.get_width() / rect_w
 	
+	# Extruded frames are surrounded by copies of their border pixels, which shouldn't be imported
+	var extrude = spritesheet_params.frame_extrude
+	
 	var rects_on_this_row = 0
-	var rect_x = spritesheet_params.frame_offset_x
-	var rect_y = spritesheet_params.frame_offset_y
+	var rect_x = spritesheet_params.frame_offset_x + extrude
+	var rect_y = spritesheet_params.frame_offset_y + extrude
 	
 	if calculated_rects_per_row > 0:
 		for i in spritesheet_params.frame_count:
@@ -142,11 +153,11 @@ func update_rects():
 			else:
 				Logger.warn("warning: rects exceed image")
 				
-			rect_x += rect_w + spritesheet_params.frame_margin_x
+			rect_x += rect_w + spritesheet_params.frame_margin_x + 2 * extrude
 			rects_on_this_row += 1
 			if rect_x + rect_w > tex.get_width() || rects_on_this_row >= spritesheet_params.frames_per_row:
-				rect_x = spritesheet_params.frame_offset_x
-				rect_y += rect_h + spritesheet_params.frame_margin_y
+				rect_x = spritesheet_params.frame_offset_x + extrude
+				rect_y += rect_h + spritesheet_params.frame_margin_y + 2 * extrude
 				rects_on_this_row = 0
 				
 	else:
@@ -1,8 +1,17 @@
 extends "res://UI/Spritesheet/SpritesheetConfigBase.gd"
 
 func _ready():
+	# warning-ignore:return_value_discarded
+	SpritesheetGenerator.connect("error_occured", self, "error_occured")
 	
 	var spritesheet_params_ui = {
+		"packing": {
+			"label": "Packing",
+			"ui_type": "enum",
+			"items": ["Grid", "Packed"],
+			"default": "Grid"
+		},
+		
 		"frames_per_row": {
 			"label": "Frames per row",
 			"ui_type": "number",
@@ -10,21 +19,102 @@ func _ready():
 			"max": 1000,
 			"default": 10
 		},
+		
+		"sep_padding": {
+			"label": "Padding",
+			"ui_type": "header",
+		},
+		
+		"spacing": {
+			"label": "Spacing",
+			"ui_type": "number",
+			"min": 0,
+			"max": 100,
+			"default": 0
+		},
+		
+		"margin": {
+			"label": "Margin",
+			"ui_type": "number",
+			"min": 0,
+			"max": 100,
+			"default": 0
+		},
+		
+		"extrude": {
+			"label": "Extrude",
+			"ui_type": "number",
+			"min": 0,
+			"max": 32,
+			"default": 0
+		},
+		
+		"sep_size": {
+			"label": "Size",
+			"ui_type": "header",
+		},
+		
+		"max_texture_size": {
+			"label": "Max size (0 = none)",
+			"ui_type": "number",
+			"min": 0,
+			"max": 16384,
+			"default": 0
+		},
+		
+		"power_of_two": {
+			"label": "Power of two",
+			"ui_type": "bool",
+			"default": false
+		},
+		
+		"sep_export": {
+			"label": "Export",
+			"ui_type": "header",
+		},
+		
+		"metadata_format": {
+			"label": "Metadata file",
+			"ui_type": "enum",
+			"items": ["None", "TexturePacker", "Aseprite", "Bitmapflow"],
+			"default": "None"
+		},
+		
+		"godot_spriteframes": {
+			"label": "Godot SpriteFrames",
+			"ui_type": "bool",
+			"default": false
+		},
 	}
 	
 	setup_spritesheet_params_ui(spritesheet_params_ui)
 	
-	self.spritesheet_params = SpritesheetGenerator.get_optimal_spritesheet_params()
-	set_ui_value("frames_per_row", spritesheet_params["frames_per_row"])
-	
+	update_optimal_params()
 	update_spritesheet()
 	
 func _on_value_changed(key, value):
 	._on_value_changed(key, value)
 	
+	if key in ["spacing", "margin", "extrude", "max_texture_size"]:
+		update_optimal_params()
+	
 	update_spritesheet()
 	
+func update_optimal_params():
+	var optimal_params = SpritesheetGenerator.get_optimal_spritesheet_params(spritesheet_params)
+	spritesheet_params["frames_per_row"] = optimal_params["frames_per_row"]
+	set_ui_value("frames_per_row", spritesheet_params["frames_per_row"])
+	
 func update_spritesheet():
 	tex = SpritesheetGenerator.generate_spritesheet(spritesheet_params)
+	if tex == null:
+		return # The error was already reported through error_occured
 	get_node(spritesheet_view_path).texture = tex
 	update_img_info()
+	
+func update_img_info():
+	var page_count = SpritesheetGenerator.get_page_count(spritesheet_params)
+	if page_count > 1:
+		get_node(img_info_path).text = "Image size: %sx%s (page 1 of %s)" % [tex.get_width(), tex.get_height(), page_count]
+	else:
+		.update_img_info()
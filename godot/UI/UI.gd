@@ -5,14 +5,34 @@ signal img_params_changed
 signal loaded_gif(filename)
 signal loaded_separate_frames(filenames)
 signal loaded_spritesheet(filename, rects)
+signal loaded_atlas(json_filename)
+signal loaded_flow_fields(filenames)
+signal loaded_companion_frames(name, filenames)
+signal loaded_region_masks(filenames)
 
 signal exported_separate_frames(filename)
 signal exported_gif(filename, fps)
-signal exported_spritesheet(filename, spritesheet_tex)
+signal exported_spritesheet(filename, spritesheet_params, fps, loop_animation)
+signal exported_aseprite(filename, fps)
+signal exported_spriteframes(filename, fps, loop_animation)
+signal exported_flowmap(filename, flowmap_params, fps)
+signal exported_flow_fields(filename)
+
+signal requested_quality_report(img_params)
+signal requested_autotune(img_params)
+signal cancelled_autotune
 
 var last_dir 
 
+# 0 means automatic: frames_per_row makes a square sheet, range uses the largest flow
+var flowmap_params = {
+	"frames_per_row": 0,
+	"range": 0.0,
+	"precision": "Bit8",
+}
+
 var imageview_path = "MarginContainer/VBoxContainer/HBoxContainer/PanelTex/MarginContainer/ImageView"
+onready var sidebar = $MarginContainer/VBoxContainer/HBoxContainer/PanelTool/VBoxContainer/Sidebar
 onready var view_options = $MarginContainer/VBoxContainer/HBoxContainer/PanelTex/ViewOptions
 onready var progressbar = $MarginContainer/VBoxContainer/ProgressBar
 onready var framecounter = $MarginContainer/VBoxContainer/HBoxContainer/PanelTex/MarginContainer/MarginContainer/FrameCounter
@@ -93,14 +113,70 @@ func load_separate_frames():
 	
 func load_spritesheet():
 	var dialog = show_file_dialog()
-	dialog.set_filters(PoolStringArray(["*.png ; PNG Spritesheet", "*.jpg ; JPG Spritesheet"]))
+	dialog.set_filters(PoolStringArray(["*.png ; PNG Spritesheet", "*.jpg ; JPG Spritesheet", "*.json ; TexturePacker/Aseprite JSON"]))
 	dialog.mode = FileDialog.MODE_OPEN_FILE
 	
 	var filename = yield(dialog, "file_selected")
 	
 	last_dir = filename.get_base_dir()
 	
-	show_spritesheet_config_load_dialog(filename)
+	if filename.ends_with(".json"):
+		emit_signal("loaded_atlas", filename)
+		clear_texture()
+	else:
+		show_spritesheet_config_load_dialog(filename)
+	
+func load_flow_fields():
+	var dialog = show_file_dialog()
+	dialog.set_filters(PoolStringArray(["*.flo ; Middlebury Flow Fields"]))
+	dialog.mode = FileDialog.MODE_OPEN_FILES
+	
+	var filenames = yield(dialog, "files_selected")
+	
+	last_dir = filenames[0].get_base_dir()
+
+	# One file per frame pair, in filename order
+	emit_signal("loaded_flow_fields", filenames)
+	
+func load_companion_frames():
+	var dialog = show_file_dialog()
+	dialog.set_filters(PoolStringArray(["*.png ; PNG Frames"]))
+	dialog.mode = FileDialog.MODE_OPEN_FILES
+	
+	var filenames = yield(dialog, "files_selected")
+	
+	last_dir = filenames[0].get_base_dir()
+	
+	# The name is used as suffix when exporting, e.g. sheet_normal.png
+	var name_dialog = ConfirmationDialog.new()
+	name_dialog.window_title = "Companion frames name"
+	var line_edit = LineEdit.new()
+	line_edit.text = "normal"
+	name_dialog.add_child(line_edit)
+	add_child(name_dialog)
+	name_dialog.popup_centered(Vector2(300, 80))
+	
+	add_bg_behind(name_dialog)
+	
+	yield(name_dialog, "confirmed")
+	var companion_name = line_edit.text.strip_edges()
+	name_dialog.queue_free()
+	if companion_name == "":
+		return
+
+	emit_signal("loaded_companion_frames", companion_name, filenames)
+	
+func load_region_masks():
+	var dialog = show_file_dialog()
+	dialog.set_filters(PoolStringArray(["*.png ; PNG Masks"]))
+	dialog.mode = FileDialog.MODE_OPEN_FILES
+	
+	var filenames = yield(dialog, "files_selected")
+	
+	last_dir = filenames[0].get_base_dir()
+
+	# One mask per frame: red keeps pixels in place, green cross-fades them
+	emit_signal("loaded_region_masks", filenames)
 	
 func show_spritesheet_config_load_dialog(filename):
 	var spritesheet_dialog = preload("res://UI/Spritesheet/SpritesheetConfigLoad.tscn").instance() 
@@ -154,16 +230,119 @@ func export_spritesheet():
 	
 	show_spritesheet_config_save_dialog(filename)
 	
+func export_aseprite():
+	var dialog = show_file_dialog()
+	dialog.set_filters(PoolStringArray(["*.aseprite ; Aseprite File", "*.ase ; Aseprite File"]))
+	dialog.mode = FileDialog.MODE_SAVE_FILE
+	
+	var filename = yield(dialog, "file_selected")
+	
+	last_dir = filename.get_base_dir()
+	
+	var speed_ratio = ImageHolder.get_speed_ratio()
+	var output_fps = GlobalHolder.fps * speed_ratio
+	emit_signal("exported_aseprite", filename, output_fps)
+	
+func export_spriteframes():
+	var dialog = show_file_dialog()
+	dialog.set_filters(PoolStringArray(["*.tres ; Godot SpriteFrames"]))
+	dialog.mode = FileDialog.MODE_SAVE_FILE
+	
+	var filename = yield(dialog, "file_selected")
+	
+	last_dir = filename.get_base_dir()
+	
+	var output_fps = GlobalHolder.fps * ImageHolder.get_speed_ratio()
+	var loop_animation = sidebar.img_params["loop_seamlessly"]
+	emit_signal("exported_spriteframes", filename, output_fps, loop_animation)
+	
+func export_flowmap():
+	var dialog = show_file_dialog()
+	dialog.set_filters(PoolStringArray(["*.png ; PNG Spritesheet"]))
+	dialog.mode = FileDialog.MODE_SAVE_FILE
+	
+	var filename = yield(dialog, "file_selected")
+	
+	last_dir = filename.get_base_dir()
+	
+	# The flow map belongs to the input frames, so use the input FPS
+	emit_signal("exported_flowmap", filename, flowmap_params, GlobalHolder.fps)
+	
+func export_flow_fields():
+	var dialog = show_file_dialog()
+	dialog.set_filters(PoolStringArray(["*.flo ; Middlebury Flow Fields", "*.pfm ; Portable Float Maps"]))
+	dialog.mode = FileDialog.MODE_SAVE_FILE
+	
+	var filename = yield(dialog, "file_selected")
+	
+	last_dir = filename.get_base_dir()
+
+	emit_signal("exported_flow_fields", filename)
+	
 func show_spritesheet_config_save_dialog(filename):
 	var spritesheet_dialog = preload("res://UI/Spritesheet/SpritesheetConfigSave.tscn").instance() 
+	spritesheet_dialog.connect("error_occured", self, "_on_SpriteSheetConfigSave_error_occured")
 	add_child(spritesheet_dialog)
 	spritesheet_dialog.popup_centered_ratio()
 	
 	add_bg_behind(spritesheet_dialog)
 	
 	yield(spritesheet_dialog, "spritesheet_confirmed")
-	var tex = spritesheet_dialog.tex
-	emit_signal("exported_spritesheet", filename, tex)
+	var output_fps = GlobalHolder.fps * ImageHolder.get_speed_ratio()
+	var loop_animation = sidebar.img_params["loop_seamlessly"]
+	emit_signal("exported_spritesheet", filename, spritesheet_dialog.spritesheet_params, output_fps, loop_animation)
+	
+# --------------------- #
+
+func evaluate_quality():
+	progressbar.message = "Evaluating interpolation quality..."
+	emit_signal("requested_quality_report", sidebar.img_params)
+	
+func _on_ImageProcessor_quality_evaluated(report):
+	var text = "Every other frame was dropped and interpolated from its neighbours.\n\n"
+	text += "Mean PSNR: %.2f dB\nMean SSIM: %.4f\nMean alpha error: %.4f\n\n" % [report["mean_psnr"], report["mean_ssim"], report["mean_alpha_error"]]
+	for frame in report["frames"]:
+		text += "Frame %d: PSNR %.2f dB, SSIM %.4f, alpha error %.4f\n" % [frame["index"] + 1, frame["psnr"], frame["ssim"], frame["alpha_error"]]
+	
+	var dialog = AcceptDialog.new()
+	dialog.window_title = "Interpolation quality"
+	dialog.dialog_text = text
+	add_child(dialog)
+	dialog.popup_centered()
+	
+	add_bg_behind(dialog)
+	
+func edit_correspondences():
+	var dialog = preload("res://UI/CorrespondenceEditor.gd").new()
+	dialog.input_frames = get_node(imageview_path).input_frames
+	dialog.loop_seamlessly = sidebar.img_params["loop_seamlessly"]
+	dialog.correspondences = sidebar.img_params.get("correspondences", []).duplicate(true)
+	add_child(dialog)
+	dialog.popup_centered_ratio()
+	
+	add_bg_behind(dialog)
+	
+	var correspondences = yield(dialog, "correspondences_confirmed")
+	sidebar.img_params["correspondences"] = correspondences
+	sidebar.emit_img_params_changed()
+	
+func autotune():
+	progressbar.message = "Auto-tuning flow parameters..."
+	emit_signal("requested_autotune", sidebar.img_params)
+	
+func cancel_autotune():
+	emit_signal("cancelled_autotune")
+	progressbar.message = "Auto-tune cancelled"
+	
+func _on_ImageProcessor_autotune_finished(img_params, report):
+	# Only the flow algorithm settings are tuned, the rest stays as it was
+	for key in img_params:
+		if key in sidebar.img_params:
+			sidebar.img_params[key] = img_params[key]
+	sidebar.update_ui()
+	sidebar.emit_img_params_changed()
+	
+	progressbar.message = "Auto-tune picked %s (PSNR %.2f dB, SSIM %.4f)" % [img_params["optflow_alg"], report["mean_psnr"], report["mean_ssim"]]
 	
 # --------------------- #
 
@@ -180,6 +359,9 @@ func _files_dropped(files, _screen):
 		if file.ends_with(".gif"):
 			emit_signal("loaded_gif", file)
 			clear_texture()
+		elif file.ends_with(".json"):
+			emit_signal("loaded_atlas", file)
+			clear_texture()
 		else:
 			show_spritesheet_config_load_dialog(file)
 	else:
@@ -193,6 +375,10 @@ func _on_menu_item_clicked(item):
 	
 func _on_ImageHolder_image_loaded(frames):
 	get_node(imageview_path).input_frames = frames
+	
+	# Correspondence points belong to the old frames
+	if sidebar.img_params.erase("correspondences"):
+		sidebar.emit_img_params_changed()
 
 func _on_ImageProcessor_image_processed(frames):
 	get_node(imageview_path).output_frames = frames
@@ -207,9 +393,21 @@ func _on_ImageProcessor_error_occured(error):
 func _on_ImageHolder_error_occured(error):
 	progressbar.error = error
 	
+func _on_ImageHolder_frames_normalized(warning):
+	var dialog = AcceptDialog.new()
+	dialog.window_title = "Frame sizes adjusted"
+	dialog.dialog_text = warning + "\nYou can change how this is done in the File menu."
+	add_child(dialog)
+	dialog.popup_centered()
+	
+	add_bg_behind(dialog)
+	
 func _on_SpriteSheetConfigLoad_error_occured(error):
 	progressbar.error = error
 	
+func _on_SpriteSheetConfigSave_error_occured(error):
+	progressbar.error = error
+	
 func _on_Sidebar_img_params_changed(img_params):
 	emit_signal("img_params_changed", img_params)
 
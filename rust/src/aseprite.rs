@@ -0,0 +1,237 @@
+use std::io::Write;
+
+use anyhow::Result;
+use flate2::{write::ZlibEncoder, Compression};
+
+use crate::{error::BitmapflowError, frame::Frame};
+
+//Writer for the .aseprite file format, see https://github.com/aseprite/aseprite/blob/main/docs/ase-file-specs.md
+//Only what we need is implemented: a single RGBA layer, one cel per frame, tags and cel user data.
+
+const HEADER_MAGIC: u16 = 0xA5E0;
+const FRAME_MAGIC: u16 = 0xF1FA;
+
+const CHUNK_LAYER: u16 = 0x2004;
+const CHUNK_CEL: u16 = 0x2005;
+const CHUNK_TAGS: u16 = 0x2018;
+const CHUNK_USER_DATA: u16 = 0x2020;
+
+const CEL_TYPE_COMPRESSED_IMAGE: u16 = 2;
+
+pub struct AsepriteFrame<'a> {
+    pub frame: &'a Frame,
+    pub duration_msec: u16,
+    pub is_keyframe: bool,
+}
+
+pub struct AsepriteTag {
+    pub name: String,
+    pub from: u16,
+    pub to: u16,
+}
+
+//Little-endian byte buffer, since that's what the spec uses everywhere
+#[derive(Default)]
+struct ByteWriter(Vec<u8>);
+
+impl ByteWriter {
+    fn byte(&mut self, v: u8) {
+        self.0.push(v);
+    }
+    fn word(&mut self, v: u16) {
+        self.0.extend(&v.to_le_bytes());
+    }
+    fn short(&mut self, v: i16) {
+        self.0.extend(&v.to_le_bytes());
+    }
+    fn dword(&mut self, v: u32) {
+        self.0.extend(&v.to_le_bytes());
+    }
+    fn zeroes(&mut self, n: usize) {
+        self.0.extend(std::iter::repeat(0).take(n));
+    }
+    fn string(&mut self, s: &str) {
+        self.word(s.len() as u16);
+        self.0.extend(s.as_bytes());
+    }
+    fn bytes(&mut self, b: &[u8]) {
+        self.0.extend(b);
+    }
+}
+
+fn chunk(chunk_type: u16, data: ByteWriter) -> ByteWriter {
+    let mut out = ByteWriter::default();
+    out.dword(data.0.len() as u32 + 6);
+    out.word(chunk_type);
+    out.bytes(&data.0);
+    out
+}
+
+fn layer_chunk() -> ByteWriter {
+    let mut data = ByteWriter::default();
+    data.word(1 | 2); //visible | editable
+    data.word(0); //normal image layer
+    data.word(0); //child level
+    data.word(0); //default width (ignored)
+    data.word(0); //default height (ignored)
+    data.word(0); //blend mode normal
+    data.byte(255); //opacity
+    data.zeroes(3);
+    data.string("Bitmapflow");
+    chunk(CHUNK_LAYER, data)
+}
+
+fn cel_chunk(frame: &Frame) -> Result<ByteWriter> {
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    encoder.write_all(frame.as_raw())?;
+    let compressed = encoder.finish()?;
+
+    let mut data = ByteWriter::default();
+    data.word(0); //layer index
+    data.short(0); //x
+    data.short(0); //y
+    data.byte(255); //opacity
+    data.word(CEL_TYPE_COMPRESSED_IMAGE);
+    data.short(0); //z-index
+    data.zeroes(5);
+    data.word(frame.width() as u16);
+    data.word(frame.height() as u16);
+    data.bytes(&compressed);
+    Ok(chunk(CHUNK_CEL, data))
+}
+
+fn cel_user_data_chunk(is_keyframe: bool) -> ByteWriter {
+    //Green for original frames, orange for generated inbetweens
+    let (text, [r, g, b]) = if is_keyframe {
+        ("keyframe", [0x4c, 0xaf, 0x50])
+    } else {
+        ("inbetween", [0xff, 0x98, 0x00])
+    };
+
+    let mut data = ByteWriter::default();
+    data.dword(1 | 2); //has text | has color
+    data.string(text);
+    data.bytes(&[r, g, b, 255]);
+    chunk(CHUNK_USER_DATA, data)
+}
+
+fn tags_chunk(tags: &[AsepriteTag]) -> ByteWriter {
+    let mut data = ByteWriter::default();
+    data.word(tags.len() as u16);
+    data.zeroes(8);
+    for tag in tags {
+        data.word(tag.from);
+        data.word(tag.to);
+        data.byte(0); //loop direction forward
+        data.word(0); //repeat forever
+        data.zeroes(6);
+        data.bytes(&[0, 0, 0]); //deprecated tag color
+        data.byte(0);
+        data.string(&tag.name);
+    }
+    chunk(CHUNK_TAGS, data)
+}
+
+fn frame_block(chunks: Vec<ByteWriter>, duration_msec: u16) -> ByteWriter {
+    let chunk_count = chunks.len();
+    let body: Vec<u8> = chunks.into_iter().flat_map(|c| c.0).collect();
+
+    let mut out = ByteWriter::default();
+    out.dword(body.len() as u32 + 16);
+    out.word(FRAME_MAGIC);
+    out.word(chunk_count.min(0xFFFF) as u16);
+    out.word(duration_msec);
+    out.zeroes(2);
+    out.dword(chunk_count as u32);
+    out.bytes(&body);
+    out
+}
+
+pub fn write_aseprite<W: Write>(
+    mut writer: W,
+    frames: &[AsepriteFrame<'_>],
+    tags: &[AsepriteTag],
+) -> Result<()> {
+    if frames.is_empty() {
+        return Err(BitmapflowError::NothingToSave.into());
+    }
+
+    //Sizes and the frame count are stored as 16 bit words
+    let (width, height) = frames[0].frame.dimensions();
+    if let Some((index, frame)) = frames
+        .iter()
+        .enumerate()
+        .find(|(_i, f)| f.frame.dimensions() != (width, height))
+    {
+        return Err(BitmapflowError::FrameSizeMismatch {
+            index,
+            size: frame.frame.dimensions(),
+            expected: (width, height),
+        }
+        .into());
+    }
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        return Err(BitmapflowError::ImageTooLarge {
+            format: "Aseprite",
+            size: (width, height),
+            max: (u16::MAX as u32, u16::MAX as u32),
+        }
+        .into());
+    }
+    if frames.len() > u16::MAX as usize {
+        return Err(BitmapflowError::TooManyFrames {
+            format: "Aseprite",
+            max: u16::MAX as usize,
+            found: frames.len(),
+        }
+        .into());
+    }
+
+    let mut frame_blocks = vec![];
+    for (i, frame) in frames.iter().enumerate() {
+        let mut chunks = vec![];
+        if i == 0 {
+            chunks.push(layer_chunk());
+            if !tags.is_empty() {
+                chunks.push(tags_chunk(tags));
+            }
+        }
+        chunks.push(cel_chunk(frame.frame)?);
+        //NOTE: user data directly after a cel chunk belongs to that cel
+        chunks.push(cel_user_data_chunk(frame.is_keyframe));
+
+        frame_blocks.push(frame_block(chunks, frame.duration_msec));
+    }
+
+    let body_len: usize = frame_blocks.iter().map(|b| b.0.len()).sum();
+
+    let mut header = ByteWriter::default();
+    header.dword(body_len as u32 + 128);
+    header.word(HEADER_MAGIC);
+    header.word(frames.len() as u16);
+    header.word(width as u16);
+    header.word(height as u16);
+    header.word(32); //color depth: RGBA
+    header.dword(1); //layer opacity has a valid value
+    header.word(frames[0].duration_msec); //deprecated speed
+    header.dword(0);
+    header.dword(0);
+    header.byte(0); //transparent palette index
+    header.zeroes(3);
+    header.word(0); //number of colors
+    header.byte(1); //pixel width
+    header.byte(1); //pixel height
+    header.short(0); //grid x
+    header.short(0); //grid y
+    header.word(16); //grid width
+    header.word(16); //grid height
+    header.zeroes(84);
+    assert!(header.0.len() == 128);
+
+    writer.write_all(&header.0)?;
+    for block in frame_blocks {
+        writer.write_all(&block.0)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,312 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use image::{
+    imageops::{replace, rotate270},
+    DynamicImage, RgbaImage,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    datatypes::SpritesheetMetadataFormat,
+    frame::{Frame, Frames},
+};
+
+//Spritesheet metadata in the TexturePacker JSON format (both the "hash" and "array" flavours).
+//Aseprite's JSON export uses the same schema, so this covers both.
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct AtlasRect {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct AtlasSize {
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AtlasFrame {
+    pub frame: AtlasRect,
+    #[serde(default)]
+    pub rotated: bool,
+    #[serde(default)]
+    pub trimmed: bool,
+    #[serde(rename = "spriteSourceSize")]
+    pub sprite_source_size: Option<AtlasRect>,
+    #[serde(rename = "sourceSize")]
+    pub source_size: Option<AtlasSize>,
+    pub duration: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct AtlasMeta {
+    image: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AtlasFile {
+    frames: Value,
+    #[serde(default)]
+    meta: AtlasMeta,
+}
+
+pub struct Atlas {
+    pub image_filename: String,
+    pub frames: Vec<AtlasFrame>,
+}
+
+impl Atlas {
+    pub fn load(json_filename: &str) -> Result<Self> {
+        let json = fs::read_to_string(json_filename)?;
+        let file: AtlasFile = serde_json::from_str(&json)?;
+
+        let frames: Vec<AtlasFrame> = match file.frames {
+            //NOTE: serde_json is built with preserve_order, so the hash keeps the frame order of the file
+            Value::Object(map) => map
+                .into_iter()
+                .map(|(_name, frame)| serde_json::from_value(frame))
+                .collect::<Result<_, _>>()?,
+            array @ Value::Array(_) => serde_json::from_value(array)?,
+            _ => return Err(anyhow!("'frames' must be an object or an array")),
+        };
+
+        if frames.is_empty() {
+            return Err(anyhow!("the atlas doesn't contain any frames"));
+        }
+
+        //The image is stored relative to the JSON file. If it isn't specified, guess it's a PNG next to it.
+        let json_path = Path::new(json_filename);
+        let image_path = match file.meta.image {
+            Some(image) => json_path.with_file_name(image),
+            None => json_path.with_extension("png"),
+        };
+
+        Ok(Atlas {
+            image_filename: image_path.to_string_lossy().to_string(),
+            frames,
+        })
+    }
+
+    pub fn extract_frames(&self, img: &DynamicImage) -> Result<Frames> {
+        self.frames
+            .iter()
+            .map(|atlas_frame| {
+                let AtlasRect { x, y, w, h } = atlas_frame.frame;
+
+                //Rotated frames are stored rotated 90 degrees clockwise, with w and h referring to the unrotated size
+                let cropped = if atlas_frame.rotated {
+                    rotate270(&img.crop_imm(x, y, h, w).to_rgba8())
+                } else {
+                    img.crop_imm(x, y, w, h).to_rgba8()
+                };
+
+                if cropped.dimensions() != (w, h) {
+                    return Err(anyhow!(
+                        "frame at ({}, {}) with size {}x{} lies outside the image",
+                        x,
+                        y,
+                        w,
+                        h
+                    ));
+                }
+
+                //Put trimmed frames back at their original position, so all frames share one size again
+                let restored = match (
+                    atlas_frame.trimmed,
+                    atlas_frame.sprite_source_size,
+                    atlas_frame.source_size,
+                ) {
+                    (true, Some(sprite_source_size), Some(source_size)) => {
+                        let mut restored = RgbaImage::new(source_size.w, source_size.h);
+                        replace(
+                            &mut restored,
+                            &cropped,
+                            sprite_source_size.x,
+                            sprite_source_size.y,
+                        );
+                        restored
+                    }
+                    _ => cropped,
+                };
+
+                Ok(Frame(restored))
+            })
+            .collect::<Result<_>>()
+            .context("failed to extract frames from atlas")
+    }
+}
+
+//Everything the exporter needs to know about a single frame in the spritesheet
+pub struct SpritesheetFrameInfo {
+    pub page: usize,
+    pub rect: AtlasRect,
+    pub sprite_source_size: AtlasRect,
+    pub source_size: AtlasSize,
+    pub duration_msec: u32,
+    pub is_keyframe: bool,
+}
+
+pub struct SpritesheetInfo {
+    //Filename and size of every page
+    pub pages: Vec<(String, (usize, usize))>,
+    pub fps: f64,
+    pub frames: Vec<SpritesheetFrameInfo>,
+    //(first, last) frame index of every original frame plus its inbetweens
+    pub segments: Vec<(usize, usize)>,
+}
+
+impl SpritesheetInfo {
+    pub fn to_json(&self, format: SpritesheetMetadataFormat) -> Option<String> {
+        let value = match format {
+            SpritesheetMetadataFormat::None => return None,
+            SpritesheetMetadataFormat::TexturePacker => self.to_texturepacker_json(),
+            SpritesheetMetadataFormat::Aseprite => self.to_aseprite_json(),
+            SpritesheetMetadataFormat::Bitmapflow => self.to_bitmapflow_json(),
+        };
+
+        Some(
+            serde_json::to_string_pretty(&value).expect("failed to serialize spritesheet metadata"),
+        )
+    }
+
+    fn frame_name(i: usize) -> String {
+        format!("frame_{:04}", i)
+    }
+
+    fn segment_name(i: usize) -> String {
+        format!("Frame {}", i + 1)
+    }
+
+    fn frame_json(frame: &SpritesheetFrameInfo) -> Value {
+        let AtlasSize { w, h } = frame.source_size;
+        let trimmed = frame.sprite_source_size != AtlasRect { x: 0, y: 0, w, h };
+        json!({
+            "frame": frame.rect,
+            "rotated": false,
+            "trimmed": trimmed,
+            "spriteSourceSize": frame.sprite_source_size,
+            "sourceSize": frame.source_size,
+            "duration": frame.duration_msec,
+            "page": frame.page,
+        })
+    }
+
+    fn meta_json(&self) -> Value {
+        //The first page is the main image, all pages are listed as well for multi-page spritesheets
+        let (image, (w, h)) = &self.pages[0];
+        let pages: Vec<Value> = self
+            .pages
+            .iter()
+            .map(|(image, (w, h))| json!({ "image": image, "size": { "w": w, "h": h } }))
+            .collect();
+
+        json!({
+            "app": "https://github.com/Bauxitedev/bitmapflow",
+            "version": env!("CARGO_PKG_VERSION"),
+            "image": image,
+            "format": "RGBA8888",
+            "size": { "w": w, "h": h },
+            "scale": "1",
+            "pages": pages,
+        })
+    }
+
+    fn to_texturepacker_json(&self) -> Value {
+        let frames: serde_json::Map<String, Value> = self
+            .frames
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| (Self::frame_name(i), Self::frame_json(frame)))
+            .collect();
+
+        json!({
+            "frames": frames,
+            "meta": self.meta_json(),
+        })
+    }
+
+    fn to_aseprite_json(&self) -> Value {
+        let frames: Vec<Value> = self
+            .frames
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| {
+                let mut value = Self::frame_json(frame);
+                value["filename"] = json!(Self::frame_name(i));
+                value
+            })
+            .collect();
+
+        let frame_tags: Vec<Value> = self
+            .segments
+            .iter()
+            .enumerate()
+            .map(|(i, (from, to))| {
+                json!({
+                    "name": Self::segment_name(i),
+                    "from": from,
+                    "to": to,
+                    "direction": "forward",
+                })
+            })
+            .collect();
+
+        let mut meta = self.meta_json();
+        meta["frameTags"] = json!(frame_tags);
+        meta["layers"] = json!([{ "name": "Bitmapflow", "opacity": 255, "blendMode": "normal" }]);
+
+        json!({
+            "frames": frames,
+            "meta": meta,
+        })
+    }
+
+    fn to_bitmapflow_json(&self) -> Value {
+        let frames: Vec<Value> = self
+            .frames
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| {
+                json!({
+                    "index": i,
+                    "page": frame.page,
+                    "x": frame.rect.x,
+                    "y": frame.rect.y,
+                    "w": frame.rect.w,
+                    "h": frame.rect.h,
+                    "offset_x": frame.sprite_source_size.x,
+                    "offset_y": frame.sprite_source_size.y,
+                    "source_w": frame.source_size.w,
+                    "source_h": frame.source_size.h,
+                    "duration_msec": frame.duration_msec,
+                    "is_keyframe": frame.is_keyframe,
+                })
+            })
+            .collect();
+
+        let tags: Vec<Value> = self
+            .segments
+            .iter()
+            .enumerate()
+            .map(|(i, (from, to))| json!({ "name": Self::segment_name(i), "from": from, "to": to }))
+            .collect();
+
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "pages": self
+                .pages
+                .iter()
+                .map(|(image, (w, h))| json!({ "image": image, "width": w, "height": h }))
+                .collect::<Vec<_>>(),
+            "fps": self.fps,
+            "frames": frames,
+            "tags": tags,
+        })
+    }
+}
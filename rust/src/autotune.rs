@@ -0,0 +1,125 @@
+use std::{
+    cmp,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use itertools::iproduct;
+use log::*;
+use rayon::prelude::*;
+
+use crate::{
+    datatypes::{FlowAlg, ImgParams},
+    frame::Frame,
+    quality::{evaluate_leave_one_out, QualityReport},
+};
+
+//Grid search over the flow algorithms and their most important parameters.
+//Every candidate gets a leave-one-out evaluation, the one that reconstructs the held out frames best wins.
+
+fn candidate_algs() -> Vec<FlowAlg> {
+    let simple_flow =
+        iproduct!(2..=5, 1..=3, &[4, 8, 12]).map(|(layers, averaging_block_size, max_flow)| {
+            FlowAlg::SimpleFlow {
+                layers,
+                averaging_block_size,
+                max_flow: *max_flow,
+            }
+        });
+
+    let dense_rlof = iproduct!(&[1, 3, 6], &[0.0, 1.0], &[false, true], &[false, true]).map(
+        |(grid_step, forward_backward_threshold, use_post_proc, use_variational_refinement)| {
+            FlowAlg::DenseRLOF {
+                forward_backward_threshold: *forward_backward_threshold,
+                grid_step_x: *grid_step,
+                grid_step_y: *grid_step,
+                use_post_proc: *use_post_proc,
+                use_variational_refinement: *use_variational_refinement,
+            }
+        },
+    );
+
+    simple_flow.chain(dense_rlof).collect()
+}
+
+//Higher is better. SSIM rewards matching structure, the alpha error punishes smeared or missing pixels.
+pub fn score(report: &QualityReport) -> f64 {
+    report.mean_ssim - report.mean_alpha_error
+}
+
+//Returns the best params (base_params with a different flow algorithm) and their report,
+//or None if cancelled was set while searching. progress is called with a value between 0 and 1.
+pub fn autotune(
+    frames: &[Frame],
+    base_params: &ImgParams,
+    cancelled: &AtomicBool,
+    progress: impl Fn(f64) + Sync,
+) -> Result<Option<(ImgParams, QualityReport)>> {
+    let candidates: Vec<ImgParams> = candidate_algs()
+        .into_iter()
+        .map(|optflow_alg| ImgParams {
+            optflow_alg,
+            ..base_params.clone()
+        })
+        .collect();
+
+    info!("Auto-tuning over {} candidates...", candidates.len());
+
+    let finished = AtomicUsize::new(0);
+    let first_error = Mutex::new(None);
+
+    let best = candidates
+        .par_iter()
+        .filter_map(|params| {
+            if cancelled.load(Ordering::Relaxed) {
+                return None;
+            }
+
+            let result =
+                evaluate_leave_one_out(frames, params, |_| !cancelled.load(Ordering::Relaxed));
+
+            let finished = finished.fetch_add(1, Ordering::Relaxed) + 1;
+            progress(finished as f64 / candidates.len() as f64);
+
+            match result {
+                Ok(Some(report)) => Some((params, report)),
+                Ok(None) => None,
+                Err(err) => {
+                    //A candidate can fail (e.g. an invalid parameter combination for these frames), skip it
+                    warn!(
+                        "Auto-tune candidate {:?} failed: {:?}",
+                        params.optflow_alg, err
+                    );
+                    first_error.lock().unwrap().get_or_insert(err);
+                    None
+                }
+            }
+        })
+        .max_by(|(_, a), (_, b)| {
+            score(a)
+                .partial_cmp(&score(b))
+                .unwrap_or(cmp::Ordering::Equal)
+        });
+
+    if cancelled.load(Ordering::Relaxed) {
+        return Ok(None);
+    }
+
+    match best {
+        Some((params, report)) => {
+            info!(
+                "Auto-tune picked {:?} with score {:.4}",
+                params.optflow_alg,
+                score(&report)
+            );
+            Ok(Some((params.clone(), report)))
+        }
+        None => Err(first_error
+            .into_inner()
+            .unwrap()
+            .unwrap_or_else(|| anyhow!("no candidate could be evaluated"))),
+    }
+}
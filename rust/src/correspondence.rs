@@ -0,0 +1,106 @@
+use opencv::{
+    core::{Mat, Size, Vec2f, CV_32FC2},
+    prelude::*,
+};
+
+use crate::{datatypes::Correspondence, error::BitmapflowResult};
+
+//Turns the sparse correspondence points the user placed into (changes to) a dense flow field
+
+//Keeps the interpolation finite right on top of a point
+const EPSILON: f32 = 1e-3;
+
+//Moves the flow towards the correspondences. Every point's correction fades out with a gaussian falloff,
+//so the flow right at a point matches it exactly and the flow further than radius away is left alone.
+pub fn correct_flow(
+    flow: &mut Mat,
+    correspondences: &[Correspondence],
+    radius: f32,
+) -> BitmapflowResult<()> {
+    let size = flow.size()?;
+    let radius = if radius > 0.0 {
+        radius
+    } else {
+        auto_radius(size)
+    };
+
+    //How far the estimated flow is off at every point
+    let mut corrections = vec![];
+    for point in correspondences {
+        let (x, y) = (
+            (point.from[0].round() as i32).clamp(0, size.width - 1),
+            (point.from[1].round() as i32).clamp(0, size.height - 1),
+        );
+        let estimated: Vec2f = *flow.at_2d(y, x)?;
+        let estimated = if estimated[0].is_finite() && estimated[1].is_finite() {
+            [estimated[0], estimated[1]]
+        } else {
+            [0.0, 0.0]
+        };
+
+        corrections.push((
+            point.from,
+            [
+                point.to[0] - point.from[0] - estimated[0],
+                point.to[1] - point.from[1] - estimated[1],
+            ],
+        ));
+    }
+
+    //The gaussian reaches about 1% of its peak at radius
+    let sigma = radius / 3.0;
+    for y in 0..size.height {
+        for x in 0..size.width {
+            let (mut total_weight, mut sum) = (0.0, [0.0, 0.0]);
+            for (from, correction) in &corrections {
+                let distance_squared = (x as f32 - from[0]).powi(2) + (y as f32 - from[1]).powi(2);
+                let weight = (-distance_squared / (2.0 * sigma * sigma)).exp();
+                total_weight += weight;
+                sum[0] += weight * correction[0];
+                sum[1] += weight * correction[1];
+            }
+
+            //Dividing by at least 1 makes overlapping points share, without blowing up far away from them
+            let total_weight = f32::max(total_weight, 1.0);
+            let value: &mut Vec2f = flow.at_2d_mut(y, x)?;
+            for c in 0..2 {
+                let estimated = if value[c].is_finite() { value[c] } else { 0.0 };
+                value[c] = estimated + sum[c] / total_weight;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Builds a flow field only from the correspondences, by inverse distance weighting
+pub fn interpolate_flow(correspondences: &[Correspondence], size: Size) -> BitmapflowResult<Mat> {
+    let mut flow = unsafe { Mat::new_size(size, CV_32FC2)? };
+
+    for y in 0..size.height {
+        for x in 0..size.width {
+            let (mut total_weight, mut sum) = (0.0, [0.0, 0.0]);
+            for point in correspondences {
+                let distance_squared =
+                    (x as f32 - point.from[0]).powi(2) + (y as f32 - point.from[1]).powi(2);
+                let weight = 1.0 / (distance_squared + EPSILON);
+                total_weight += weight;
+                sum[0] += weight * (point.to[0] - point.from[0]);
+                sum[1] += weight * (point.to[1] - point.from[1]);
+            }
+
+            *flow.at_2d_mut::<Vec2f>(y, x)? = if total_weight > 0.0 {
+                Vec2f::from([sum[0] / total_weight, sum[1] / total_weight])
+            } else {
+                Vec2f::from([0.0, 0.0])
+            };
+        }
+    }
+
+    Ok(flow)
+}
+
+//A quarter of the smaller frame dimension
+fn auto_radius(size: Size) -> f32 {
+    (size.width.min(size.height) as f32 / 4.0).max(1.0)
+}
@@ -6,7 +6,7 @@ use gdnative::{
     prelude::*,
 };
 use opencv::core::Vec2f;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct ImgParams {
@@ -16,19 +16,243 @@ pub struct ImgParams {
     #[serde(flatten)]
     pub optflow_alg: FlowAlg,
     pub show_motion_vectors: bool,
+    #[serde(default)]
+    pub motion_vector_mode: MotionVectorMode,
+    //Flow magnitude (in pixels) shown at full brightness, 0 means the largest flow in the animation
+    #[serde(default)]
+    pub motion_vector_scale: f32,
+    #[serde(default)]
+    pub motion_vector_legend: bool,
+    //For seamlessly tiling textures, flow and warping wrap around the edges
+    #[serde(default)]
+    pub tileable: bool,
+    //What the warper does when the flow points outside the frame
+    #[serde(default)]
+    pub edge_mode: EdgeMode,
+    //Removes global jitter between the input frames before estimating the flow
+    #[serde(default)]
+    pub stabilization: Stabilization,
+    //Moves the output frames back to where the input frames were, so only the jitter gets smoothed
+    #[serde(default)]
+    pub reapply_offsets: bool,
+    //Fitted per frame pair before the local flow, for scrolling and panning scenes
+    #[serde(default)]
+    pub global_motion: GlobalMotion,
+    //Gives every separate sprite in the frame its own flow, for particles, projectiles etc.
+    #[serde(default)]
+    pub segment_objects: bool,
+    //Matching points placed by the user, one list per frame pair
+    #[serde(default)]
+    pub correspondences: Vec<Vec<Correspondence>>,
+    #[serde(default)]
+    pub correspondence_mode: CorrespondenceMode,
+    //Distance (in pixels) over which a correspondence point corrects the estimated flow, 0 means automatic
+    #[serde(default)]
+    pub correspondence_radius: f32,
+    //Clean-up steps between estimating and applying the flow
+    #[serde(flatten)]
+    pub flow_refinement: FlowRefinement,
+    //How much every flow is blended with the flows of the neighbouring frame pairs (0 to 1), against flicker
+    #[serde(default)]
+    pub temporal_smoothing: f32,
+    //Inbetweens follow a curve through three frames instead of a straight line between two
+    #[serde(default)]
+    pub quadratic_motion: bool,
+    //What to show where warping reveals areas that were covered in the earlier frame
+    #[serde(default)]
+    pub hole_fill: HoleFill,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum HoleFill {
+    //Keep whatever the flow points at
+    None,
+    //Take the pixel from the next frame
+    FrameB,
+    //Good for sprites, the revealed area is usually background
+    Transparent,
+    //Blend in the surrounding colours
+    Diffusion,
+    //Copy the best matching pixels from nearby
+    Patch,
+}
+
+impl Default for HoleFill {
+    fn default() -> Self {
+        HoleFill::None
+    }
+}
+
+//All steps are off by default. They run in the order of the fields.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(default)]
+pub struct FlowRefinement {
+    //Vectors that differ more than this (in pixels) from the median of their 3x3 neighbourhood
+    //are replaced by that median, 0 means off
+    pub outlier_threshold: f32,
+    //Radius of the median filter, 0 means off
+    pub median_radius: usize,
+    //Smooths the flow without blurring it across edges in the colour frame
+    pub edge_aware_filter: EdgeAwareFilter,
+    pub edge_aware_radius: usize,
+    //How different two colours (0-255) can be and still get smoothed together
+    pub edge_aware_color_sigma: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum EdgeAwareFilter {
+    None,
+    JointBilateral,
+    Guided,
+}
+
+impl Default for EdgeAwareFilter {
+    fn default() -> Self {
+        EdgeAwareFilter::None
+    }
+}
+
+//A point in frame a and where it should end up in frame b, in pixels
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Correspondence {
+    pub from: [f32; 2],
+    pub to: [f32; 2],
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum CorrespondenceMode {
+    //Bend the estimated flow towards the points
+    Correct,
+    //Ignore the estimated flow and interpolate all motion from the points
+    Interpolate,
+}
+
+impl Default for CorrespondenceMode {
+    fn default() -> Self {
+        CorrespondenceMode::Correct
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum EdgeMode {
+    //Repeat the border pixels
+    Clamp,
+    Transparent,
+    //Reflect at the border, without repeating the border pixel itself
+    Mirror,
+    Wrap,
+}
+
+impl Default for EdgeMode {
+    fn default() -> Self {
+        EdgeMode::Clamp
+    }
+}
+
+impl EdgeMode {
+    //Maps a coordinate that may be out of bounds into 0..size, or None if it should be transparent
+    pub fn apply(self, coord: i32, size: i32) -> Option<i32> {
+        if (0..size).contains(&coord) {
+            return Some(coord);
+        }
+
+        match self {
+            EdgeMode::Clamp => Some(coord.clamp(0, size - 1)),
+            EdgeMode::Transparent => None,
+            EdgeMode::Mirror => {
+                if size == 1 {
+                    return Some(0);
+                }
+                let period = 2 * (size - 1);
+                let coord = coord.rem_euclid(period);
+                Some(if coord < size { coord } else { period - coord })
+            }
+            EdgeMode::Wrap => Some(coord.rem_euclid(size)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Stabilization {
+    None,
+    //Centre of mass of the alpha channel
+    AlphaCentroid,
+    //Translation that best lines up the whole frame with the first one
+    PhaseCorrelation,
+    //Lowest opaque row and horizontal alpha centroid, keeps the feet on the ground
+    FeetBaseline,
+}
+
+impl Default for Stabilization {
+    fn default() -> Self {
+        Stabilization::None
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum GlobalMotion {
+    None,
+    Translation,
+    //Translation, rotation, scale and shear
+    Affine,
+    //Also handles perspective, e.g. a camera turning
+    Homography,
+}
+
+impl Default for GlobalMotion {
+    fn default() -> Self {
+        GlobalMotion::None
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum MotionVectorMode {
+    //Hue is the direction, brightness the magnitude
+    ColorWheel,
+    //Sparse arrows drawn over the original frame
+    Arrows,
+}
+
+impl Default for MotionVectorMode {
+    fn default() -> Self {
+        MotionVectorMode::ColorWheel
+    }
+}
+
+//The parameter structs are passed to and from Godot as dictionaries, by way of JSON
+pub fn from_json_variant<T: DeserializeOwned>(variant: &Variant) -> Result<T, FromVariantError> {
+    let dict = variant
+        .try_to_dictionary()
+        .ok_or_else(|| FromVariantError::InvalidVariantType {
+            variant_type: variant.get_type(),
+            expected: VariantType::Dictionary,
+        })?;
+    let json = dict.to_json().to_string();
+    serde_json::from_str(&json).map_err(|e| FromVariantError::Custom(e.to_string()))
+}
+
+pub fn to_json_variant<T: Serialize>(value: &T) -> Variant {
+    let json = serde_json::to_string(value)
+        .unwrap_or_else(|_| panic!("failed to serialize {} as JSON", std::any::type_name::<T>()));
+
+    let variant = JSON::godot_singleton()
+        .parse(json)
+        .expect("failed to parse JSON");
+
+    let variant = unsafe { variant.assume_safe() }.result();
+    assert!(variant.get_type() == VariantType::Dictionary);
+    variant
 }
 
 impl FromVariant for ImgParams {
     fn from_variant(variant: &Variant) -> Result<Self, FromVariantError> {
-        let dict =
-            variant
-                .try_to_dictionary()
-                .ok_or_else(|| FromVariantError::InvalidVariantType {
-                    variant_type: variant.get_type(),
-                    expected: VariantType::Dictionary,
-                })?;
-        let json = dict.to_json().to_string();
-        serde_json::from_str(&json).map_err(|e| FromVariantError::Custom(e.to_string()))
+        from_json_variant(variant)
+    }
+}
+
+impl ToVariant for ImgParams {
+    fn to_variant(&self) -> Variant {
+        to_json_variant(self)
     }
 }
 
@@ -61,34 +285,164 @@ impl Default for FlowAlg {
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct SpritesheetGenerationParams {
     pub frames_per_row: usize,
+    #[serde(default)]
+    pub metadata_format: SpritesheetMetadataFormat,
+    #[serde(default)]
+    pub godot_spriteframes: bool,
+    #[serde(default)]
+    pub packing: SpritesheetPacking,
+    //Empty pixels between frames
+    #[serde(default)]
+    pub spacing: usize,
+    //Empty pixels around the whole spritesheet
+    #[serde(default)]
+    pub margin: usize,
+    //Amount of times the border pixels of every frame are repeated outwards, to avoid bleeding when filtering
+    #[serde(default)]
+    pub extrude: usize,
+    //Maximum width and height of a single page, 0 means unlimited. Frames that don't fit go to the next page.
+    #[serde(default)]
+    pub max_texture_size: usize,
+    #[serde(default)]
+    pub power_of_two: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum SpritesheetPacking {
+    //Untrimmed frames on a uniform grid
+    Grid,
+    //Frames trimmed to their alpha bounding box, deduplicated and bin-packed
+    Packed,
+}
+
+impl Default for SpritesheetPacking {
+    fn default() -> Self {
+        SpritesheetPacking::Grid
+    }
+}
+
+//Format of the JSON file that's saved next to an exported spritesheet
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum SpritesheetMetadataFormat {
+    None,
+    TexturePacker,
+    Aseprite,
+    Bitmapflow,
+}
+
+impl Default for SpritesheetMetadataFormat {
+    fn default() -> Self {
+        SpritesheetMetadataFormat::None
+    }
 }
 
 impl FromVariant for SpritesheetGenerationParams {
     fn from_variant(variant: &Variant) -> Result<Self, FromVariantError> {
-        let dict =
-            variant
-                .try_to_dictionary()
-                .ok_or_else(|| FromVariantError::InvalidVariantType {
-                    variant_type: variant.get_type(),
-                    expected: VariantType::Dictionary,
-                })?;
-        let json = dict.to_json().to_string();
-        serde_json::from_str(&json).map_err(|e| FromVariantError::Custom(e.to_string()))
+        from_json_variant(variant)
     }
 }
 
 impl ToVariant for SpritesheetGenerationParams {
     fn to_variant(&self) -> Variant {
-        let json = serde_json::to_string(self)
-            .expect("failed to serialize SpritesheetGenerationParams as JSON");
+        to_json_variant(self)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FlowMapParams {
+    //0 means choose automatically
+    pub frames_per_row: usize,
+    //Flow (in pixels) that maps to the edges of the encoded range, 0 means use the largest flow
+    pub range: f32,
+    #[serde(default)]
+    pub precision: FlowMapPrecision,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FlowMapPrecision {
+    Bit8,
+    Bit16,
+}
+
+impl Default for FlowMapPrecision {
+    fn default() -> Self {
+        FlowMapPrecision::Bit8
+    }
+}
+
+impl FromVariant for FlowMapParams {
+    fn from_variant(variant: &Variant) -> Result<Self, FromVariantError> {
+        from_json_variant(variant)
+    }
+}
+
+//What to do when the loaded frames aren't all the same size
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FrameSizeParams {
+    #[serde(default)]
+    pub mode: FrameSizeMode,
+    //Where the original frame ends up when padding or cropping
+    #[serde(default)]
+    pub anchor: FrameAnchor,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FrameSizeMode {
+    //Refuse to load the frames
+    Reject,
+    //Add transparent pixels until every frame is as large as the largest one
+    PadToLargest,
+    //Cut every frame down to the smallest one
+    CropToSmallest,
+    //Resize every frame to the size most frames already have
+    Scale,
+}
+
+impl Default for FrameSizeMode {
+    fn default() -> Self {
+        FrameSizeMode::PadToLargest
+    }
+}
 
-        let variant = JSON::godot_singleton()
-            .parse(json)
-            .expect("failed to parse JSON");
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FrameAnchor {
+    Center,
+    //Keeps the feet of a character on the same line
+    BottomCenter,
+    TopLeft,
+}
+
+impl Default for FrameAnchor {
+    fn default() -> Self {
+        FrameAnchor::Center
+    }
+}
+
+impl FrameAnchor {
+    //Offset of a frame inside a larger one, per axis. Negative if the frame is larger than the target.
+    pub fn offset(self, size: (u32, u32), target: (u32, u32)) -> (i64, i64) {
+        let (dx, dy) = (
+            target.0 as i64 - size.0 as i64,
+            target.1 as i64 - size.1 as i64,
+        );
+
+        match self {
+            FrameAnchor::Center => (dx / 2, dy / 2),
+            FrameAnchor::BottomCenter => (dx / 2, dy),
+            FrameAnchor::TopLeft => (0, 0),
+        }
+    }
+}
 
-        let variant = unsafe { variant.assume_safe() }.result();
-        assert!(variant.get_type() == VariantType::Dictionary);
-        variant
+impl FromVariant for FrameSizeParams {
+    fn from_variant(variant: &Variant) -> Result<Self, FromVariantError> {
+        from_json_variant(variant)
+    }
+}
+
+impl ToVariant for FrameSizeParams {
+    fn to_variant(&self) -> Variant {
+        to_json_variant(self)
     }
 }
 
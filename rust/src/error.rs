@@ -0,0 +1,101 @@
+use thiserror::Error;
+
+use crate::frame::Frame;
+
+//Errors that can happen anywhere in the load → process → save pipeline.
+//The messages are shown to the user as-is, so they should say what to do about it.
+#[derive(Error, Debug)]
+pub enum BitmapflowError {
+    //Loading
+    #[error("{0} doesn't contain any frames")]
+    NoFramesLoaded(String),
+    #[error("expected {expected} frames to match the loaded animation, found {found}")]
+    FrameCountMismatch { expected: usize, found: usize },
+    #[error(
+        "companion set {name} has {found} frames, but the animation has {expected}. It's skipped"
+    )]
+    CompanionCountMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    #[error(
+        "there are {found} region masks, but the animation has {expected} frames. They're skipped"
+    )]
+    RegionMaskCountMismatch { expected: usize, found: usize },
+    #[error("expected one flow field per frame pair ({pairs}, or {} when looping), found {found}", .pairs + 1)]
+    FlowFieldCountMismatch { pairs: usize, found: usize },
+    #[error("{filename} is {}x{}, but the frames are {}x{}", .size.0, .size.1, .expected.0, .expected.1)]
+    FlowFieldSizeMismatch {
+        filename: String,
+        size: (usize, usize),
+        expected: (u32, u32),
+    },
+
+    //Processing
+    #[error("there are no frames yet, load an animation first")]
+    NoFrames,
+    #[error("frame {index} is {}x{}, but frame 0 is {}x{}. All frames need to be the same size", .size.0, .size.1, .expected.0, .expected.1)]
+    FrameSizeMismatch {
+        index: usize,
+        size: (u32, u32),
+        expected: (u32, u32),
+    },
+    #[error("unknown optical flow algorithm {0}, pick SimpleFlow or DenseRLOF instead")]
+    InvalidFlowAlg(String),
+    #[error(
+        "a quality report or auto-tune is already running, wait for it to finish or cancel it"
+    )]
+    EvaluationRunning,
+    #[error(
+        "temporal smoothing and curved motion aren't applied to frames that are split into objects"
+    )]
+    TemporalWithSegmentation,
+    #[error("OpenCV error: {0}")]
+    OpenCv(#[from] opencv::Error),
+
+    //Saving
+    #[error("there's nothing to save yet, wait until processing is done")]
+    NothingToSave,
+    #[error("no flow available yet, wait until processing is done")]
+    NoFlowsYet,
+    #[error("the flow was calculated for {flows} frames, but {frames} are loaded. Wait until processing is done")]
+    FlowsOutOfDate { flows: usize, frames: usize },
+    #[error("{format} files can be at most {}x{} pixels, these frames are {}x{}", .max.0, .max.1, .size.0, .size.1)]
+    ImageTooLarge {
+        format: &'static str,
+        size: (u32, u32),
+        max: (u32, u32),
+    },
+    #[error("{format} files can have at most {max} frames, there are {found}")]
+    TooManyFrames {
+        format: &'static str,
+        max: usize,
+        found: usize,
+    },
+    #[error("couldn't encode GIF: {0}")]
+    Gif(#[from] gif::EncodingError),
+}
+
+pub type BitmapflowResult<T> = Result<T, BitmapflowError>;
+
+//Returns the size shared by all frames, or an error if there are none or they differ
+pub fn check_frame_sizes(frames: &[Frame]) -> BitmapflowResult<(u32, u32)> {
+    let expected = frames
+        .first()
+        .ok_or(BitmapflowError::NoFrames)?
+        .dimensions();
+
+    match frames
+        .iter()
+        .enumerate()
+        .find(|(_i, frame)| frame.dimensions() != expected)
+    {
+        Some((index, frame)) => Err(BitmapflowError::FrameSizeMismatch {
+            index,
+            size: frame.dimensions(),
+            expected,
+        }),
+        None => Ok(expected),
+    }
+}
@@ -0,0 +1,184 @@
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use opencv::{
+    core::{Mat, Vec2f, CV_32FC2},
+    prelude::*,
+};
+
+//A dense flow field, stored as one (dx, dy) vector per pixel.
+//Unlike a Mat this is plain Rust data, so it can be kept around after processing and shared between threads.
+#[derive(Clone, Debug)]
+pub struct FlowField {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<[f32; 2]>,
+}
+
+impl FlowField {
+    pub fn get(&self, x: usize, y: usize) -> [f32; 2] {
+        self.data[y * self.width + x]
+    }
+
+    //Length of the longest (finite) vector in the field
+    pub fn max_magnitude(&self) -> f32 {
+        self.data
+            .iter()
+            .filter(|[dx, dy]| dx.is_finite() && dy.is_finite())
+            .map(|[dx, dy]| (dx * dx + dy * dy).sqrt())
+            .fold(0.0, f32::max)
+    }
+}
+
+impl From<&Mat> for FlowField {
+    fn from(mat: &Mat) -> Self {
+        assert!(mat.depth().unwrap() == opencv::core::CV_32F);
+        assert!(mat.channels().unwrap() == 2);
+
+        let size = mat.size().unwrap();
+        let (width, height) = (size.width as usize, size.height as usize);
+
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let flow: Vec2f = *mat
+                    .at_2d(y as i32, x as i32)
+                    .expect("Expected a 2-channel 32-bit floating point image");
+                data.push([flow[0], flow[1]]);
+            }
+        }
+
+        FlowField {
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+impl From<&FlowField> for Mat {
+    fn from(field: &FlowField) -> Self {
+        let mut mat = unsafe {
+            Mat::new_rows_cols(field.height as i32, field.width as i32, CV_32FC2).unwrap()
+        };
+
+        for y in 0..field.height {
+            for x in 0..field.width {
+                *mat.at_2d_mut::<Vec2f>(y as i32, x as i32)
+                    .expect("flow field addressing failed") = Vec2f::from(field.get(x, y));
+            }
+        }
+
+        mat
+    }
+}
+
+//Middlebury .flo format, see http://vision.middlebury.edu/flow/code/flow-code/README.txt
+const FLO_TAG: f32 = 202021.25;
+//Components bigger than this mean "unknown flow"
+const FLO_UNKNOWN_THRESHOLD: f32 = 1e9;
+
+impl FlowField {
+    pub fn write_flo(&self, filename: &str) -> Result<()> {
+        let mut bytes = Vec::with_capacity(12 + self.data.len() * 8);
+        bytes.extend(&FLO_TAG.to_le_bytes());
+        bytes.extend(&(self.width as i32).to_le_bytes());
+        bytes.extend(&(self.height as i32).to_le_bytes());
+
+        for [dx, dy] in &self.data {
+            for v in &[*dx, *dy] {
+                let v = if v.is_finite() {
+                    *v
+                } else {
+                    FLO_UNKNOWN_THRESHOLD * 10.0
+                };
+                bytes.extend(&v.to_le_bytes());
+            }
+        }
+
+        fs::write(filename, bytes)?;
+        Ok(())
+    }
+
+    pub fn read_flo(filename: &str) -> Result<Self> {
+        let bytes = fs::read(filename)?;
+        if bytes.len() < 12 {
+            return Err(anyhow!("file is too small to be a .flo file"));
+        }
+
+        let read_4 = |offset: usize| {
+            [
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ]
+        };
+
+        if f32::from_le_bytes(read_4(0)) != FLO_TAG {
+            return Err(anyhow!("missing PIEH tag, this is not a .flo file"));
+        }
+
+        let width = i32::from_le_bytes(read_4(4));
+        let height = i32::from_le_bytes(read_4(8));
+        if width <= 0 || height <= 0 {
+            return Err(anyhow!("invalid size {}x{}", width, height));
+        }
+        let (width, height) = (width as usize, height as usize);
+
+        let expected_len = width
+            .checked_mul(height)
+            .and_then(|n| n.checked_mul(8))
+            .and_then(|n| n.checked_add(12))
+            .ok_or_else(|| anyhow!("flow field size {}x{} is too large", width, height))?;
+        if bytes.len() != expected_len {
+            return Err(anyhow!(
+                "expected {} bytes of flow data for a {}x{} flow field, found {}",
+                expected_len - 12,
+                width,
+                height,
+                bytes.len() - 12
+            ));
+        }
+
+        let data = bytes[12..]
+            .array_chunks::<8>()
+            .map(|[a, b, c, d, e, f, g, h]| {
+                let unknown_to_nan = |v: f32| {
+                    if v.abs() > FLO_UNKNOWN_THRESHOLD {
+                        f32::NAN
+                    } else {
+                        v
+                    }
+                };
+                [
+                    unknown_to_nan(f32::from_le_bytes([*a, *b, *c, *d])),
+                    unknown_to_nan(f32::from_le_bytes([*e, *f, *g, *h])),
+                ]
+            })
+            .collect();
+
+        Ok(FlowField {
+            width,
+            height,
+            data,
+        })
+    }
+
+    //Portable float map with 3 channels, the third one is always zero. Rows are stored bottom to top.
+    pub fn write_pfm(&self, filename: &str) -> Result<()> {
+        let mut bytes = format!("PF\n{} {}\n-1.0\n", self.width, self.height).into_bytes();
+
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                let [dx, dy] = self.get(x, y);
+                for v in &[dx, dy, 0.0] {
+                    bytes.extend(&v.to_le_bytes());
+                }
+            }
+        }
+
+        fs::write(filename, bytes)?;
+        Ok(())
+    }
+}
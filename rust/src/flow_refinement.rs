@@ -0,0 +1,171 @@
+use std::cmp::Ordering;
+
+use opencv::{
+    core::{self, Mat, BORDER_DEFAULT, CV_32F, CV_32FC3},
+    prelude::*,
+    types::VectorOfMat,
+    ximgproc,
+};
+
+use crate::{
+    datatypes::{EdgeAwareFilter, FlowRefinement},
+    error::BitmapflowResult,
+    flow::FlowField,
+};
+
+//Cleans up the raw flow before it's applied. SimpleFlow in particular produces isolated outlier
+//vectors, which turn into single-pixel sparkles in the inbetweens.
+
+//Radius of the neighbourhood the outlier rejection compares every vector with
+const OUTLIER_RADIUS: usize = 1;
+//Used when edge_aware_radius or edge_aware_color_sigma are 0
+const DEFAULT_EDGE_AWARE_RADIUS: usize = 4;
+const DEFAULT_EDGE_AWARE_COLOR_SIGMA: f32 = 25.0;
+
+//guide is the colour frame the flow starts from, as a BGR Mat
+pub fn refine_flow(
+    flow: &mut Mat,
+    guide: &Mat,
+    refinement: &FlowRefinement,
+) -> BitmapflowResult<()> {
+    let FlowRefinement {
+        outlier_threshold,
+        median_radius,
+        edge_aware_filter,
+        edge_aware_radius,
+        edge_aware_color_sigma,
+    } = *refinement;
+
+    if outlier_threshold > 0.0 || median_radius > 0 {
+        let mut field = FlowField::from(&*flow);
+        if outlier_threshold > 0.0 {
+            field = reject_outliers(&field, outlier_threshold);
+        }
+        if median_radius > 0 {
+            field = median_filter(&field, median_radius);
+        }
+        *flow = Mat::from(&field);
+    }
+
+    if edge_aware_filter != EdgeAwareFilter::None {
+        let radius = if edge_aware_radius > 0 {
+            edge_aware_radius
+        } else {
+            DEFAULT_EDGE_AWARE_RADIUS
+        } as i32;
+        let color_sigma = if edge_aware_color_sigma > 0.0 {
+            edge_aware_color_sigma
+        } else {
+            DEFAULT_EDGE_AWARE_COLOR_SIGMA
+        } as f64;
+
+        //The joint bilateral filter needs the guide to have the same depth as the flow
+        let mut float_guide = Mat::default()?;
+        guide.convert_to(&mut float_guide, CV_32FC3, 1.0, 0.0)?;
+
+        //The filters would spread non-finite vectors into their neighbours
+        let mut field = FlowField::from(&*flow);
+        for vector in &mut field.data {
+            if !vector[0].is_finite() || !vector[1].is_finite() {
+                *vector = [0.0, 0.0];
+            }
+        }
+
+        //The joint bilateral filter only takes 1 or 3 channel sources, so every component is filtered separately
+        let mut components = VectorOfMat::new();
+        core::split(&Mat::from(&field), &mut components)?;
+
+        let mut filtered_components = VectorOfMat::new();
+        for component in components.iter() {
+            let mut filtered = Mat::default()?;
+            match edge_aware_filter {
+                EdgeAwareFilter::JointBilateral => ximgproc::joint_bilateral_filter(
+                    &float_guide,
+                    &component,
+                    &mut filtered,
+                    2 * radius + 1,
+                    color_sigma,
+                    radius as f64,
+                    BORDER_DEFAULT,
+                )?,
+                //eps is in squared intensity units
+                EdgeAwareFilter::Guided => ximgproc::guided_filter(
+                    &float_guide,
+                    &component,
+                    &mut filtered,
+                    radius,
+                    color_sigma * color_sigma,
+                    CV_32F,
+                )?,
+                EdgeAwareFilter::None => unreachable!(),
+            }
+            filtered_components.push(filtered);
+        }
+
+        let mut filtered = Mat::default()?;
+        core::merge(&filtered_components, &mut filtered)?;
+        *flow = filtered;
+    }
+
+    Ok(())
+}
+
+//Replaces every vector that's far from the median of its neighbourhood with that median
+fn reject_outliers(field: &FlowField, threshold: f32) -> FlowField {
+    map_neighbourhoods(field, OUTLIER_RADIUS, |vector, median| {
+        let distance = ((vector[0] - median[0]).powi(2) + (vector[1] - median[1]).powi(2)).sqrt();
+        if distance.is_finite() && distance <= threshold {
+            vector
+        } else {
+            median
+        }
+    })
+}
+
+fn median_filter(field: &FlowField, radius: usize) -> FlowField {
+    map_neighbourhoods(field, radius, |_vector, median| median)
+}
+
+//Calls f with every vector and the per-component median of the (finite) vectors around it
+fn map_neighbourhoods(
+    field: &FlowField,
+    radius: usize,
+    f: impl Fn([f32; 2], [f32; 2]) -> [f32; 2],
+) -> FlowField {
+    let (w, h) = (field.width, field.height);
+    let mut data = Vec::with_capacity(field.data.len());
+    let (mut xs, mut ys) = (vec![], vec![]);
+
+    for y in 0..h {
+        for x in 0..w {
+            xs.clear();
+            ys.clear();
+            for ny in y.saturating_sub(radius)..(y + radius + 1).min(h) {
+                for nx in x.saturating_sub(radius)..(x + radius + 1).min(w) {
+                    let [dx, dy] = field.get(nx, ny);
+                    if dx.is_finite() && dy.is_finite() {
+                        xs.push(dx);
+                        ys.push(dy);
+                    }
+                }
+            }
+
+            let median = [median(&mut xs), median(&mut ys)];
+            data.push(f(field.get(x, y), median));
+        }
+    }
+
+    FlowField {
+        width: w,
+        height: h,
+        data,
+    }
+}
+
+fn median(values: &mut [f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    values[values.len() / 2]
+}
@@ -0,0 +1,143 @@
+use std::f32::consts::PI;
+
+use bracket_color::prelude::*;
+use image::{Pixel, Rgba, RgbaImage};
+use opencv::core::Vec2f;
+
+use crate::{datatypes::BVector2, flow::FlowField, frame::Frame};
+
+//Size of the colour wheel legend, in pixels
+const LEGEND_SIZE: u32 = 32;
+const LEGEND_MARGIN: u32 = 2;
+
+fn to_byte(f: f32) -> u8 {
+    (f * 255.0).round() as u8
+}
+
+//Hue is the direction of the flow, brightness is its magnitude (relative to max_magnitude)
+fn flow_to_color(flow: [f32; 2], max_magnitude: f32) -> Rgba<u8> {
+    let flow = BVector2::from(Vec2f::from(flow));
+    let magnitude = flow.length() / max_magnitude;
+    let angle_normalized = flow.angle_from_x_axis().positive().get() / (PI * 2.0);
+    let col = HSV::from_f32(angle_normalized, 1.0, magnitude.clamp(0.0, 1.0)).to_rgb();
+    Rgba::from([to_byte(col.r), to_byte(col.g), to_byte(col.b), 255])
+}
+
+pub fn flow_to_color_frame(flow: &FlowField, max_magnitude: f32) -> Frame {
+    let max_magnitude = max_magnitude.max(f32::EPSILON);
+
+    let inner = RgbaImage::from_fn(flow.width as u32, flow.height as u32, |x, y| {
+        let [dx, dy] = flow.get(x as usize, y as usize);
+
+        if dx.is_finite() && dy.is_finite() {
+            flow_to_color([dx, dy], max_magnitude)
+        } else {
+            Rgba::from([0, 0, 0, 0]) //if NaN output a transparent pixel
+        }
+    });
+
+    Frame(inner)
+}
+
+//Draws a line with Bresenham's algorithm, clipped to the image
+fn draw_line(img: &mut RgbaImage, from: (i32, i32), to: (i32, i32), color: Rgba<u8>) {
+    let (mut x, mut y) = from;
+    let (dx, dy) = ((to.0 - x).abs(), -(to.1 - y).abs());
+    let (sx, sy) = ((to.0 - x).signum(), (to.1 - y).signum());
+    let mut err = dx + dy;
+
+    loop {
+        if x >= 0 && y >= 0 && (x as u32) < img.width() && (y as u32) < img.height() {
+            img.put_pixel(x as u32, y as u32, color);
+        }
+        if (x, y) == to {
+            break;
+        }
+
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+    }
+}
+
+//Draws sparse arrows on top of the frame, one per grid cell. The longest arrow is as long as a grid cell.
+pub fn flow_to_arrow_frame(frame: &Frame, flow: &FlowField, max_magnitude: f32) -> Frame {
+    let max_magnitude = max_magnitude.max(f32::EPSILON);
+    let mut img = frame.0.clone();
+
+    let step = (flow.width.min(flow.height) / 16).max(4);
+    let arrow_scale = step as f32 / max_magnitude;
+
+    for y in (step / 2..flow.height).step_by(step) {
+        for x in (step / 2..flow.width).step_by(step) {
+            let [dx, dy] = flow.get(x, y);
+            if !dx.is_finite() || !dy.is_finite() {
+                continue;
+            }
+
+            let (ax, ay) = (dx * arrow_scale, dy * arrow_scale);
+            let length = (ax * ax + ay * ay).sqrt();
+            let color = flow_to_color([dx, dy], max_magnitude / 2.0); //Brighter than the colour wheel, so arrows stand out
+
+            let start = (x as i32, y as i32);
+            if length < 1.0 {
+                draw_line(&mut img, start, start, color);
+                continue;
+            }
+
+            let end = (
+                (x as f32 + ax).round() as i32,
+                (y as f32 + ay).round() as i32,
+            );
+            draw_line(&mut img, start, end, color);
+
+            //Arrow head, two short lines at +-150 degrees from the arrow direction
+            let head_length = (length * 0.35).max(2.0);
+            let angle = ay.atan2(ax);
+            for side in &[-1.0f32, 1.0] {
+                let head_angle = angle + side * PI * 5.0 / 6.0;
+                let head_end = (
+                    (end.0 as f32 + head_angle.cos() * head_length).round() as i32,
+                    (end.1 as f32 + head_angle.sin() * head_length).round() as i32,
+                );
+                draw_line(&mut img, end, head_end, color);
+            }
+        }
+    }
+
+    Frame(img)
+}
+
+//Draws the colour wheel in the bottom right corner, so you can tell which colour means which direction.
+//The rim of the wheel is the flow shown at full brightness.
+pub fn draw_color_wheel_legend(frame: &mut Frame) {
+    let (w, h) = (frame.width(), frame.height());
+    let size = LEGEND_SIZE
+        .min(w.saturating_sub(LEGEND_MARGIN * 2))
+        .min(h.saturating_sub(LEGEND_MARGIN * 2));
+    if size < 4 {
+        return; //Frame too small for a legend
+    }
+
+    let (left, top) = (w - size - LEGEND_MARGIN, h - size - LEGEND_MARGIN);
+    let radius = size as f32 / 2.0;
+
+    for y in 0..size {
+        for x in 0..size {
+            let (dx, dy) = (x as f32 + 0.5 - radius, y as f32 + 0.5 - radius);
+            if dx * dx + dy * dy > radius * radius {
+                continue;
+            }
+
+            let color = flow_to_color([dx, dy], radius);
+            let pixel = frame.0.get_pixel_mut(left + x, top + y);
+            pixel.blend(&color);
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use image::{ImageBuffer, Rgb, RgbImage};
+
+use crate::flow::FlowField;
+
+//Encodes flow fields as images for motion vector flipbooks.
+//Every pixel stores the flow in the red (x) and green (y) channel as 0.5 + flow / (2 * range),
+//so a value of 0.5 means no motion, and 0 and 1 mean a motion of -range and +range pixels.
+
+fn encode(v: f32, range: f32) -> f32 {
+    if v.is_finite() {
+        (0.5 + v / (2.0 * range)).clamp(0.0, 1.0)
+    } else {
+        0.5
+    }
+}
+
+pub fn encode_flow_8bit(flow: &FlowField, range: f32) -> RgbImage {
+    ImageBuffer::from_fn(flow.width as u32, flow.height as u32, |x, y| {
+        let [dx, dy] = flow.get(x as usize, y as usize);
+        let to_byte = |v: f32| (encode(v, range) * 255.0).round() as u8;
+        Rgb([to_byte(dx), to_byte(dy), 0])
+    })
+}
+
+pub fn encode_flow_16bit(flow: &FlowField, range: f32) -> ImageBuffer<Rgb<u16>, Vec<u16>> {
+    ImageBuffer::from_fn(flow.width as u32, flow.height as u32, |x, y| {
+        let [dx, dy] = flow.get(x as usize, y as usize);
+        let to_word = |v: f32| (encode(v, range) * 65535.0).round() as u16;
+        Rgb([to_word(dx), to_word(dy), 0])
+    })
+}
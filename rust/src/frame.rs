@@ -1,23 +1,22 @@
-use std::{f32::consts::PI, ops::Deref};
+use std::ops::Deref;
 
-use bracket_color::prelude::*;
 use gdnative::{
     api::{Image, ImageTexture},
     core_types::{FromVariant, FromVariantError, ToVariant, TypedArray, Variant},
     prelude::*,
 };
-use image::{Rgba, RgbaImage};
+use image::RgbaImage;
 use imgref::Img;
 use opencv::{
-    core::{Vec2f, Vec3, CV_8UC3},
+    core::{Vec3, CV_8UC3},
     prelude::*,
 };
 
-use crate::datatypes::BVector2;
-
 #[derive(Clone, Debug)]
 pub struct Frame(pub FrameInner);
 pub type Frames = Vec<Frame>;
+//Named frame sets (e.g. normal or emissive maps) that match the colour frames one to one
+pub type CompanionFrames = Vec<(String, Frames)>;
 type FrameInner = RgbaImage;
 
 //Little hack so we can pretend a Frame is an Img<Vec<...>> and call its methods directly
@@ -121,49 +120,3 @@ impl From<&Frame> for Mat {
         mat
     }
 }
-
-pub fn flow_mat_to_frame(input: &Mat) -> Frame {
-    let w = input.size().unwrap().width as u32;
-    let h = input.size().unwrap().height as u32;
-
-    assert!(input.depth().unwrap() == opencv::core::CV_32F);
-    assert!(input.channels().unwrap() == 2);
-
-    let to_byte = |f: f32| (f * 255.0).round() as u8;
-
-    let inner = RgbaImage::from_fn(w, h, |x, y| {
-        let color: Vec2f = *input
-            .at_2d(y as i32, x as i32)
-            .expect("Expected a 2-channel 32-bit floating point image");
-
-        let flow: BVector2 = color.into();
-
-        if flow.x.is_finite() && flow.y.is_finite() {
-            let magnitude = flow.length() * 0.3;
-            let angle_normalized = flow.angle_from_x_axis().positive().get() / (PI * 2.0);
-            let col = HSV::from_f32(angle_normalized, 1.0, magnitude.clamp(0.0, 1.0)).to_rgb();
-            Rgba::from([to_byte(col.r), to_byte(col.g), to_byte(col.b), 255])
-        } else {
-            Rgba::from([0, 0, 0, 0]) //if NaN output a transparent pixel
-        }
-    });
-
-    Frame(inner)
-}
-
-//TODO: impl From<Ref<ImageTexture, Shared>> for Frame?
-pub fn texture_to_image(img: Ref<ImageTexture, Shared>) -> RgbaImage {
-    let img = unsafe { img.assume_safe() };
-
-    let data = img.get_data().unwrap();
-    let data = unsafe { data.assume_safe() };
-    let data = data.get_data();
-    let data = data.read();
-
-    RgbaImage::from_vec(
-        img.get_width() as u32,
-        img.get_height() as u32,
-        data.to_vec(),
-    )
-    .unwrap()
-}
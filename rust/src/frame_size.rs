@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use image::{
+    imageops::{self, FilterType},
+    RgbaImage,
+};
+
+use crate::{
+    datatypes::{FrameSizeMode, FrameSizeParams},
+    error::{check_frame_sizes, BitmapflowResult},
+    frame::{Frame, Frames},
+};
+
+//Frames exported by different people or tools often differ by a pixel or two.
+//Instead of refusing them, they can be brought to a common size here.
+
+//Returns the normalised frames and the indices of the frames that had to be changed
+pub fn normalize_frame_sizes(
+    frames: Frames,
+    params: &FrameSizeParams,
+) -> BitmapflowResult<(Frames, Vec<usize>)> {
+    match check_frame_sizes(&frames) {
+        Ok(_) => return Ok((frames, vec![])),
+        Err(err) if params.mode == FrameSizeMode::Reject => return Err(err),
+        Err(_) => {}
+    }
+
+    let target = target_size(&frames, params.mode);
+
+    let mut adjusted = vec![];
+    let frames = frames
+        .into_iter()
+        .enumerate()
+        .map(|(i, frame)| {
+            if frame.dimensions() != target {
+                adjusted.push(i);
+            }
+            fit_to_size(frame, target, params)
+        })
+        .collect();
+
+    Ok((frames, adjusted))
+}
+
+//Brings a frame to the target size the way normalize_frame_sizes does.
+//Companion frames and region masks go through this too, so they keep lining up with their frames.
+pub fn fit_to_size(frame: Frame, target: (u32, u32), params: &FrameSizeParams) -> Frame {
+    if frame.dimensions() == target {
+        return frame;
+    }
+
+    match params.mode {
+        FrameSizeMode::PadToLargest | FrameSizeMode::CropToSmallest => place(
+            &frame,
+            target,
+            params.anchor.offset(frame.dimensions(), target),
+        ),
+        //Frames of the wrong size are rejected before they get here
+        FrameSizeMode::Scale | FrameSizeMode::Reject => Frame(imageops::resize(
+            &frame.0,
+            target.0,
+            target.1,
+            FilterType::Triangle,
+        )),
+    }
+}
+
+fn target_size(frames: &[Frame], mode: FrameSizeMode) -> (u32, u32) {
+    let sizes = frames.iter().map(|frame| frame.dimensions());
+
+    match mode {
+        FrameSizeMode::PadToLargest => {
+            sizes.fold((0, 0), |(w, h), (fw, fh)| (w.max(fw), h.max(fh)))
+        }
+        FrameSizeMode::CropToSmallest => sizes.fold((u32::MAX, u32::MAX), |(w, h), (fw, fh)| {
+            (w.min(fw), h.min(fh))
+        }),
+        //The most common size, ties go to the size that appears first
+        FrameSizeMode::Scale | FrameSizeMode::Reject => {
+            let mut counts: HashMap<(u32, u32), usize> = HashMap::new();
+            for size in sizes.clone() {
+                *counts.entry(size).or_default() += 1;
+            }
+            let most = counts.values().copied().max().unwrap_or(0);
+            sizes
+                .into_iter()
+                .find(|size| counts[size] == most)
+                .unwrap_or((0, 0))
+        }
+    }
+}
+
+//Copies frame onto a transparent frame of the target size, with its top left corner at offset.
+//Whatever falls outside is cut off, so this handles both padding and cropping.
+fn place(frame: &Frame, target: (u32, u32), offset: (i64, i64)) -> Frame {
+    let mut placed = RgbaImage::new(target.0, target.1);
+
+    for (x, y, pixel) in frame.enumerate_pixels() {
+        let (tx, ty) = (x as i64 + offset.0, y as i64 + offset.1);
+        if tx >= 0 && ty >= 0 && tx < target.0 as i64 && ty < target.1 as i64 {
+            placed.put_pixel(tx as u32, ty as u32, *pixel);
+        }
+    }
+
+    Frame(placed)
+}
@@ -0,0 +1,224 @@
+use log::*;
+use opencv::{
+    calib3d,
+    core::{
+        Mat, Point2f, Scalar, Size, TermCriteria, TermCriteria_COUNT, TermCriteria_EPS, Vec2f,
+        BORDER_REPLICATE, BORDER_WRAP, CV_32FC2,
+    },
+    imgproc,
+    prelude::*,
+    types::{VectorOfPoint2f, VectorOff32, VectorOfu8},
+    video,
+};
+
+use crate::{datatypes::GlobalMotion, error::BitmapflowResult};
+
+//For scrolling and panning scenes most of the flow is one global motion, which local flow
+//estimators get wrong in flat areas. The global motion is fitted to tracked features first,
+//the local flow only has to find what's left.
+
+//Feature tracking settings, fewer tracked points than MIN_POINTS means there's no reliable global motion
+const MAX_FEATURES: i32 = 500;
+const MIN_POINTS: usize = 4;
+const RANSAC_THRESHOLD: f64 = 3.0;
+
+type Transform = [[f64; 3]; 3];
+
+//Computes the flow from mat_a to mat_b as a global motion plus the local flow calculated by calc_local_flow
+pub fn calc_flow_with_global_motion(
+    mat_a: &Mat,
+    mat_b: &Mat,
+    model: GlobalMotion,
+    tileable: bool,
+    calc_local_flow: impl Fn(&Mat, &Mat) -> BitmapflowResult<Mat>,
+) -> BitmapflowResult<Mat> {
+    let transform = match fit_global_motion(mat_a, mat_b, model)? {
+        Some(transform) => transform,
+        None => {
+            warn!("Couldn't find enough features to fit the global motion, using local flow only");
+            return calc_local_flow(mat_a, mat_b);
+        }
+    };
+
+    //Undo the global motion, so frame b lines up with frame a
+    let mut aligned_b = Mat::default()?;
+    imgproc::warp_perspective(
+        mat_b,
+        &mut aligned_b,
+        &Mat::from_slice_2d(&transform)?,
+        mat_b.size()?,
+        imgproc::INTER_LINEAR | imgproc::WARP_INVERSE_MAP,
+        if tileable {
+            BORDER_WRAP
+        } else {
+            BORDER_REPLICATE
+        },
+        Scalar::default(),
+    )?;
+
+    let residual = calc_local_flow(mat_a, &aligned_b)?;
+
+    //A pixel p of frame a ends up at transform(p + residual(p)) in frame b
+    let size = residual.size()?;
+    let mut flow = unsafe { Mat::new_size(size, CV_32FC2)? };
+    for y in 0..size.height {
+        for x in 0..size.width {
+            let r: Vec2f = *residual.at_2d(y, x)?;
+            let (r_x, r_y) = if r[0].is_finite() && r[1].is_finite() {
+                (r[0] as f64, r[1] as f64)
+            } else {
+                (0.0, 0.0)
+            };
+
+            let (new_x, new_y) = apply_transform(&transform, x as f64 + r_x, y as f64 + r_y);
+            *flow.at_2d_mut::<Vec2f>(y, x)? =
+                Vec2f::from([(new_x - x as f64) as f32, (new_y - y as f64) as f32]);
+        }
+    }
+
+    Ok(flow)
+}
+
+//Returns the transform that maps points in mat_a to mat_b, or None if it couldn't be determined
+fn fit_global_motion(
+    mat_a: &Mat,
+    mat_b: &Mat,
+    model: GlobalMotion,
+) -> BitmapflowResult<Option<Transform>> {
+    if model == GlobalMotion::None {
+        return Ok(None);
+    }
+
+    let (gray_a, gray_b) = (to_gray(mat_a)?, to_gray(mat_b)?);
+
+    let mut points_a = VectorOfPoint2f::new();
+    imgproc::good_features_to_track(
+        &gray_a,
+        &mut points_a,
+        MAX_FEATURES,
+        0.01,
+        3.0,
+        &Mat::default()?,
+        3,
+        false,
+        0.04,
+    )?;
+    if points_a.len() < MIN_POINTS {
+        return Ok(None);
+    }
+
+    let mut points_b = VectorOfPoint2f::new();
+    let mut status = VectorOfu8::new();
+    let mut errors = VectorOff32::new();
+    video::calc_optical_flow_pyr_lk(
+        &gray_a,
+        &gray_b,
+        &points_a,
+        &mut points_b,
+        &mut status,
+        &mut errors,
+        Size::new(21, 21),
+        3,
+        TermCriteria::new(TermCriteria_COUNT + TermCriteria_EPS, 30, 0.01)?,
+        0,
+        1e-4,
+    )?;
+
+    //Only keep the points that were tracked successfully
+    let (tracked_a, tracked_b): (Vec<Point2f>, Vec<Point2f>) = points_a
+        .iter()
+        .zip(points_b.iter())
+        .zip(status.iter())
+        .filter(|(_, status)| *status == 1)
+        .map(|(points, _)| points)
+        .unzip();
+    if tracked_a.len() < MIN_POINTS {
+        return Ok(None);
+    }
+
+    let transform = match model {
+        GlobalMotion::None => return Ok(None),
+        //The median is robust against the points on moving objects
+        GlobalMotion::Translation => {
+            let median = |mut values: Vec<f64>| {
+                values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+                values[values.len() / 2]
+            };
+            let dx = median(
+                tracked_a
+                    .iter()
+                    .zip(&tracked_b)
+                    .map(|(a, b)| (b.x - a.x) as f64)
+                    .collect(),
+            );
+            let dy = median(
+                tracked_a
+                    .iter()
+                    .zip(&tracked_b)
+                    .map(|(a, b)| (b.y - a.y) as f64)
+                    .collect(),
+            );
+
+            [[1.0, 0.0, dx], [0.0, 1.0, dy], [0.0, 0.0, 1.0]]
+        }
+        GlobalMotion::Affine => {
+            let affine = calib3d::estimate_affine_2d(
+                &VectorOfPoint2f::from(tracked_a),
+                &VectorOfPoint2f::from(tracked_b),
+                &mut Mat::default()?,
+                calib3d::RANSAC,
+                RANSAC_THRESHOLD,
+                2000,
+                0.99,
+                10,
+            )?;
+            if affine.empty()? {
+                return Ok(None);
+            }
+
+            let at = |row, col| -> BitmapflowResult<f64> { Ok(*affine.at_2d::<f64>(row, col)?) };
+            [
+                [at(0, 0)?, at(0, 1)?, at(0, 2)?],
+                [at(1, 0)?, at(1, 1)?, at(1, 2)?],
+                [0.0, 0.0, 1.0],
+            ]
+        }
+        GlobalMotion::Homography => {
+            let homography = calib3d::find_homography(
+                &VectorOfPoint2f::from(tracked_a),
+                &VectorOfPoint2f::from(tracked_b),
+                &mut Mat::default()?,
+                calib3d::RANSAC,
+                RANSAC_THRESHOLD,
+            )?;
+            if homography.empty()? {
+                return Ok(None);
+            }
+
+            let at =
+                |row, col| -> BitmapflowResult<f64> { Ok(*homography.at_2d::<f64>(row, col)?) };
+            [
+                [at(0, 0)?, at(0, 1)?, at(0, 2)?],
+                [at(1, 0)?, at(1, 1)?, at(1, 2)?],
+                [at(2, 0)?, at(2, 1)?, at(2, 2)?],
+            ]
+        }
+    };
+
+    Ok(Some(transform))
+}
+
+fn apply_transform(transform: &Transform, x: f64, y: f64) -> (f64, f64) {
+    let [row_x, row_y, row_w] = transform;
+    let w = row_w[0] * x + row_w[1] * y + row_w[2];
+    (
+        (row_x[0] * x + row_x[1] * y + row_x[2]) / w,
+        (row_y[0] * x + row_y[1] * y + row_y[2]) / w,
+    )
+}
+
+fn to_gray(mat: &Mat) -> BitmapflowResult<Mat> {
+    let mut gray = Mat::default()?;
+    imgproc::cvt_color(mat, &mut gray, imgproc::COLOR_BGR2GRAY, 0)?;
+    Ok(gray)
+}
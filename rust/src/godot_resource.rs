@@ -0,0 +1,137 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::spritesheet_generator::SpritesheetLayoutFrame;
+
+//Writes Godot 3 text resources (.tres), see https://docs.godotengine.org/en/3.2/development/file_formats/tscn.html
+//Paths are stored relative to the .tres file, which Godot resolves relative to the resource's own directory.
+
+pub enum SpriteFramesSource<'a> {
+    //A spritesheet (possibly with multiple pages), sliced up with an AtlasTexture per frame
+    Spritesheet {
+        page_filenames: &'a [String],
+        frames: &'a [SpritesheetLayoutFrame],
+    },
+    //One texture per frame
+    SeparateFrames {
+        filenames: &'a [String],
+    },
+}
+
+//Godot only resolves relative paths here, so files outside the .tres directory get ".." components
+fn relative_path(tres_filename: &str, filename: &str) -> Result<String> {
+    let tres_dir = Path::new(tres_filename)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    let tres_components: Vec<Component> = tres_dir.components().collect();
+    let components: Vec<Component> = Path::new(filename).components().collect();
+
+    let common = tres_components
+        .iter()
+        .zip(&components)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    //Paths on another drive (or a relative path next to an absolute one) can't be reached from the .tres
+    let has_root = |components: &[Component]| {
+        components
+            .iter()
+            .any(|c| matches!(c, Component::Prefix(_) | Component::RootDir))
+    };
+    if has_root(&tres_components[common..]) || has_root(&components[common..]) {
+        return Err(anyhow!(
+            "{} can't be referenced from {}, save it on the same drive as the .tres file",
+            filename,
+            tres_filename
+        ));
+    }
+
+    let mut relative = PathBuf::new();
+    for _ in common..tres_components.len() {
+        relative.push("..");
+    }
+    relative.extend(&components[common..]);
+
+    Ok(relative.to_string_lossy().replace('\\', "/"))
+}
+
+pub fn write_spriteframes(
+    tres_filename: &str,
+    source: SpriteFramesSource<'_>,
+    fps: f64,
+    loop_animation: bool,
+) -> Result<()> {
+    let mut ext_resources = String::new();
+    let mut sub_resources = String::new();
+    let mut frame_refs = vec![];
+
+    match source {
+        SpriteFramesSource::Spritesheet {
+            page_filenames,
+            frames,
+        } => {
+            for (page, page_filename) in page_filenames.iter().enumerate() {
+                writeln!(
+                    ext_resources,
+                    "[ext_resource path=\"{}\" type=\"Texture\" id={}]",
+                    relative_path(tres_filename, page_filename)?,
+                    page + 1
+                )?;
+            }
+            ext_resources.push('\n');
+
+            for (i, frame) in frames.iter().enumerate() {
+                let id = i + 1;
+                let rect = frame.rect;
+                //The margin puts trimmed frames back at their original position and size
+                let offset = frame.sprite_source_size;
+                let (extra_w, extra_h) = (
+                    frame.source_size.w - offset.w,
+                    frame.source_size.h - offset.h,
+                );
+                writeln!(
+                    sub_resources,
+                    "[sub_resource type=\"AtlasTexture\" id={}]\natlas = ExtResource( {} )\nregion = Rect2( {}, {}, {}, {} )\nmargin = Rect2( {}, {}, {}, {} )\n",
+                    id, frame.page + 1, rect.x, rect.y, rect.w, rect.h, offset.x, offset.y, extra_w, extra_h
+                )?;
+                frame_refs.push(format!("SubResource( {} )", id));
+            }
+        }
+        SpriteFramesSource::SeparateFrames { filenames } => {
+            for (i, filename) in filenames.iter().enumerate() {
+                let id = i + 1;
+                writeln!(
+                    ext_resources,
+                    "[ext_resource path=\"{}\" type=\"Texture\" id={}]",
+                    relative_path(tres_filename, filename)?,
+                    id
+                )?;
+                frame_refs.push(format!("ExtResource( {} )", id));
+            }
+            ext_resources.push('\n');
+        }
+    }
+
+    //load_steps is the amount of ext_resources + sub_resources + the resource itself
+    let load_steps = ext_resources.matches("[ext_resource").count()
+        + sub_resources.matches("[sub_resource").count()
+        + 1;
+
+    let tres = format!(
+        "[gd_resource type=\"SpriteFrames\" load_steps={} format=2]\n\n{}{}[resource]\nanimations = [ {{\n\"frames\": [ {} ],\n\"loop\": {},\n\"name\": \"default\",\n\"speed\": {:?}\n}} ]\n",
+        load_steps,
+        ext_resources,
+        sub_resources,
+        frame_refs.join(", "),
+        loop_animation,
+        fps
+    );
+
+    fs::write(tres_filename, tres)?;
+    Ok(())
+}
@@ -0,0 +1,204 @@
+use image::Rgba;
+
+use crate::{datatypes::HoleFill, flow::FlowField, frame::Frame};
+
+//Warping can reveal areas that were covered in frame a (disocclusions). The warper has nothing
+//sensible to show there, it just repeats whatever the flow happens to point at.
+
+//A pixel is a hole if the pixel it samples from doesn't move (within this many pixels) to where it's shown
+const CONSISTENCY_THRESHOLD: f32 = 1.0;
+//Patch based filling compares (2r+1)x(2r+1) patches, looking up to SEARCH_RADIUS pixels away
+const PATCH_RADIUS: i32 = 1;
+const SEARCH_RADIUS: i32 = 8;
+
+//Finds the pixels of frame warped by flow * flow_multiplier that don't come from anywhere consistent
+pub fn find_holes(flow: &FlowField, flow_multiplier: f32) -> Vec<bool> {
+    let (w, h) = (flow.width as i32, flow.height as i32);
+    let vector = |x: i32, y: i32| {
+        let [dx, dy] = flow.get(x as usize, y as usize);
+        if dx.is_finite() && dy.is_finite() {
+            [dx * flow_multiplier, dy * flow_multiplier]
+        } else {
+            [0.0, 0.0]
+        }
+    };
+
+    (0..h)
+        .flat_map(|y| (0..w).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let [dx, dy] = vector(x, y);
+            let (source_x, source_y) = ((x as f32 - dx).round(), (y as f32 - dy).round());
+            if source_x < 0.0 || source_y < 0.0 || source_x >= w as f32 || source_y >= h as f32 {
+                //Outside the frame is up to the edge mode
+                return false;
+            }
+
+            let [source_dx, source_dy] = vector(source_x as i32, source_y as i32);
+            let (error_x, error_y) = (
+                source_x + source_dx - x as f32,
+                source_y + source_dy - y as f32,
+            );
+            (error_x * error_x + error_y * error_y).sqrt() > CONSISTENCY_THRESHOLD
+        })
+        .collect()
+}
+
+//t is how far the frame is between frame a and frame_b, flow is the (unscaled) flow between them
+pub fn fill_holes(
+    frame: &mut Frame,
+    holes: &[bool],
+    fill: HoleFill,
+    frame_b: &Frame,
+    flow: &FlowField,
+    t: f32,
+) {
+    let (w, h) = frame.dimensions();
+
+    match fill {
+        HoleFill::None => {}
+        HoleFill::Transparent => {
+            for (i, _) in holes.iter().enumerate().filter(|(_, hole)| **hole) {
+                frame
+                    .0
+                    .put_pixel(i as u32 % w, i as u32 / w, Rgba([0, 0, 0, 0]));
+            }
+        }
+        //Where this pixel will be once frame b is reached
+        HoleFill::FrameB => {
+            for (i, _) in holes.iter().enumerate().filter(|(_, hole)| **hole) {
+                let (x, y) = (i as u32 % w, i as u32 / w);
+                let [dx, dy] = flow.get(x as usize, y as usize);
+                let (dx, dy) = if dx.is_finite() && dy.is_finite() {
+                    (dx, dy)
+                } else {
+                    (0.0, 0.0)
+                };
+
+                let target_x = (x as f32 + (1.0 - t) * dx)
+                    .round()
+                    .clamp(0.0, (w - 1) as f32);
+                let target_y = (y as f32 + (1.0 - t) * dy)
+                    .round()
+                    .clamp(0.0, (h - 1) as f32);
+                let pixel = frame_b[(target_x as u32, target_y as u32)];
+                frame.0.put_pixel(x, y, pixel);
+            }
+        }
+        HoleFill::Diffusion => fill_inwards(frame, holes, |frame, known, x, y| {
+            diffuse(frame, known, x, y)
+        }),
+        HoleFill::Patch => fill_inwards(frame, holes, |frame, known, x, y| {
+            best_patch(frame, known, x, y).or_else(|| diffuse(frame, known, x, y))
+        }),
+    }
+}
+
+//Fills the holes from their edges inwards, one ring of pixels at a time.
+//fill_pixel gets the frame, which pixels are known and the pixel to fill.
+fn fill_inwards(
+    frame: &mut Frame,
+    holes: &[bool],
+    fill_pixel: impl Fn(&Frame, &[bool], i32, i32) -> Option<Rgba<u8>>,
+) {
+    let w = frame.width() as i32;
+    let mut known: Vec<bool> = holes.iter().map(|hole| !hole).collect();
+    let mut remaining: Vec<usize> = (0..holes.len()).filter(|i| holes[*i]).collect();
+
+    while !remaining.is_empty() {
+        let filled: Vec<(usize, Rgba<u8>)> = remaining
+            .iter()
+            .filter_map(|&i| {
+                fill_pixel(frame, &known, i as i32 % w, i as i32 / w).map(|pixel| (i, pixel))
+            })
+            .collect();
+
+        if filled.is_empty() {
+            //The rest has no known pixels around it at all
+            break;
+        }
+
+        for (i, pixel) in &filled {
+            frame
+                .0
+                .put_pixel(*i as u32 % w as u32, *i as u32 / w as u32, *pixel);
+            known[*i] = true;
+        }
+        remaining.retain(|i| !known[*i]);
+    }
+}
+
+//Average of the known 8 neighbours, None if there are none
+fn diffuse(frame: &Frame, known: &[bool], x: i32, y: i32) -> Option<Rgba<u8>> {
+    let (w, h) = (frame.width() as i32, frame.height() as i32);
+    let mut sum = [0u32; 4];
+    let mut count = 0;
+
+    for ny in (y - 1).max(0)..=(y + 1).min(h - 1) {
+        for nx in (x - 1).max(0)..=(x + 1).min(w - 1) {
+            if known[(ny * w + nx) as usize] {
+                let pixel = frame[(nx as u32, ny as u32)];
+                for c in 0..4 {
+                    sum[c] += pixel[c] as u32;
+                }
+                count += 1;
+            }
+        }
+    }
+
+    if count == 0 {
+        return None;
+    }
+    Some(Rgba([
+        (sum[0] / count) as u8,
+        (sum[1] / count) as u8,
+        (sum[2] / count) as u8,
+        (sum[3] / count) as u8,
+    ]))
+}
+
+//Copies the centre of the fully known patch nearby that best matches the known pixels around (x, y).
+//None if (x, y) has no known neighbours to compare with, or there's no fully known patch in range.
+fn best_patch(frame: &Frame, known: &[bool], x: i32, y: i32) -> Option<Rgba<u8>> {
+    let (w, h) = (frame.width() as i32, frame.height() as i32);
+    let is_known =
+        |x: i32, y: i32| x >= 0 && y >= 0 && x < w && y < h && known[(y * w + x) as usize];
+
+    let offsets: Vec<(i32, i32)> = (-PATCH_RADIUS..=PATCH_RADIUS)
+        .flat_map(|dy| (-PATCH_RADIUS..=PATCH_RADIUS).map(move |dx| (dx, dy)))
+        .filter(|&(dx, dy)| is_known(x + dx, y + dy))
+        .collect();
+    if offsets.is_empty() {
+        return None;
+    }
+
+    let mut best: Option<(u32, Rgba<u8>)> = None;
+    for cy in (y - SEARCH_RADIUS).max(PATCH_RADIUS)..=(y + SEARCH_RADIUS).min(h - 1 - PATCH_RADIUS)
+    {
+        for cx in
+            (x - SEARCH_RADIUS).max(PATCH_RADIUS)..=(x + SEARCH_RADIUS).min(w - 1 - PATCH_RADIUS)
+        {
+            let candidate_known = (-PATCH_RADIUS..=PATCH_RADIUS)
+                .all(|dy| (-PATCH_RADIUS..=PATCH_RADIUS).all(|dx| is_known(cx + dx, cy + dy)));
+            if !candidate_known {
+                continue;
+            }
+
+            let distance: u32 = offsets
+                .iter()
+                .map(|&(dx, dy)| {
+                    let a = frame[((x + dx) as u32, (y + dy) as u32)];
+                    let b = frame[((cx + dx) as u32, (cy + dy) as u32)];
+                    (0..4)
+                        .map(|c| (a[c] as i32 - b[c] as i32).pow(2) as u32)
+                        .sum::<u32>()
+                })
+                .sum();
+
+            if best.map_or(true, |(best_distance, _)| distance < best_distance) {
+                best = Some((distance, frame[(cx as u32, cy as u32)]));
+            }
+        }
+    }
+
+    best.map(|(_, pixel)| pixel)
+}
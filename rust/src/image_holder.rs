@@ -1,22 +1,35 @@
 use std::fs::File;
 
-use anyhow::{Error, Result};
+use anyhow::{anyhow, Error, Result};
 use gdnative::prelude::*;
 use image::{io::Reader as ImageReader, RgbaImage};
 use log::*;
 use rayon::prelude::*;
 
-use crate::frame::{Frame, Frames};
+use crate::{
+    atlas::Atlas,
+    datatypes::{FrameSizeMode, FrameSizeParams},
+    error::BitmapflowError,
+    frame::{Frame, Frames},
+    frame_size::{fit_to_size, normalize_frame_sizes},
+};
 
 type Base = Node;
 //Base refers to the type ImageHolder inherits from. In this case it's Node (because #[inherit(Node)])
 
 #[derive(NativeClass)]
 #[inherit(Base)]
-#[register_with(Self::register_signals)]
+#[register_with(Self::register)]
 pub struct ImageHolder {
-    input_frames: Frames,
+    pub input_frames: Frames,
     pub output_frames: Frames,
+    pub frame_size_params: FrameSizeParams,
+    //The sizes of the input frames before normalising and how they were normalised,
+    //companion frames and region masks have to match these
+    source_frame_sizes: Vec<(u32, u32)>,
+    source_frame_size_params: FrameSizeParams,
+    //How long every input frame is shown in milliseconds, if the source stores it
+    input_durations_msec: Vec<Option<u32>>,
 }
 
 impl ImageHolder {
@@ -24,10 +37,20 @@ impl ImageHolder {
         ImageHolder {
             input_frames: vec![],
             output_frames: vec![],
+            frame_size_params: FrameSizeParams::default(),
+            source_frame_sizes: vec![],
+            source_frame_size_params: FrameSizeParams::default(),
+            input_durations_msec: vec![],
         }
     }
 
-    fn register_signals(builder: &ClassBuilder<Self>) {
+    fn register(builder: &ClassBuilder<Self>) {
+        builder
+            .add_property("frame_size_params")
+            .with_setter(|this, _owner, value: FrameSizeParams| this.frame_size_params = value)
+            .with_getter(|this, _owner| this.frame_size_params.clone())
+            .done();
+
         builder.add_signal(Signal {
             name: "image_loaded",
             args: &[SignalArgument {
@@ -37,6 +60,41 @@ impl ImageHolder {
                 usage: PropertyUsage::DEFAULT,
             }],
         });
+        builder.add_signal(Signal {
+            name: "companion_frames_loaded",
+            args: &[
+                SignalArgument {
+                    name: "name",
+                    default: Variant::from_str("normal"),
+                    export_info: ExportInfo::new(VariantType::GodotString),
+                    usage: PropertyUsage::DEFAULT,
+                },
+                SignalArgument {
+                    name: "frames",
+                    default: Variant::from_array(&VariantArray::new_shared()),
+                    export_info: ExportInfo::new(VariantType::VariantArray),
+                    usage: PropertyUsage::DEFAULT,
+                },
+            ],
+        });
+        builder.add_signal(Signal {
+            name: "region_masks_loaded",
+            args: &[SignalArgument {
+                name: "masks",
+                default: Variant::from_array(&VariantArray::new_shared()),
+                export_info: ExportInfo::new(VariantType::VariantArray),
+                usage: PropertyUsage::DEFAULT,
+            }],
+        });
+        builder.add_signal(Signal {
+            name: "frames_normalized",
+            args: &[SignalArgument {
+                name: "warning",
+                default: Variant::from_str("Dummy warning"),
+                export_info: ExportInfo::new(VariantType::GodotString),
+                usage: PropertyUsage::DEFAULT,
+            }],
+        });
         builder.add_signal(Signal {
             name: "image_load_failure",
             args: &[SignalArgument {
@@ -48,8 +106,55 @@ impl ImageHolder {
         });
     }
 
-    fn update_input_frames(&mut self, owner: TRef<'_, Base>, frames: Frames) {
+    //Every animation needs at least one frame, and all frames need to be the same size.
+    //Frames that differ are normalised according to frame_size_params, and the user is warned about them.
+    fn check_loaded_frames(
+        &mut self,
+        owner: TRef<'_, Base>,
+        source: &str,
+        frames: Frames,
+    ) -> Result<Frames, Error> {
+        if frames.is_empty() {
+            return Err(BitmapflowError::NoFramesLoaded(source.to_string()).into());
+        }
+
+        let source_sizes = frames.iter().map(|frame| frame.dimensions()).collect();
+        let (frames, adjusted) = normalize_frame_sizes(frames, &self.frame_size_params)?;
+        self.source_frame_sizes = source_sizes;
+        self.source_frame_size_params = self.frame_size_params.clone();
+        if !adjusted.is_empty() {
+            let (width, height) = frames[0].dimensions();
+            let action = match self.frame_size_params.mode {
+                FrameSizeMode::PadToLargest => "padded",
+                FrameSizeMode::CropToSmallest => "cropped",
+                FrameSizeMode::Scale | FrameSizeMode::Reject => "scaled",
+            };
+            //Frame numbers start at 1 in the UI
+            let frame_numbers: Vec<String> = adjusted.iter().map(|i| (i + 1).to_string()).collect();
+
+            let warning = format!(
+                "Not all frames in {} had the same size. Frame(s) {} were {} to {}x{}.",
+                source,
+                frame_numbers.join(", "),
+                action,
+                width,
+                height
+            );
+            warn!("{}", warning);
+            owner.emit_signal("frames_normalized", &[Variant::from_str(warning)]);
+        }
+
+        Ok(frames)
+    }
+
+    fn update_input_frames(
+        &mut self,
+        owner: TRef<'_, Base>,
+        frames: Frames,
+        durations_msec: Vec<Option<u32>>,
+    ) {
         self.input_frames = frames;
+        self.input_durations_msec = durations_msec;
         owner.emit_signal("image_loaded", &[self.input_frames.to_variant()]);
     }
 
@@ -92,6 +197,39 @@ impl ImageHolder {
         Ok(new_input_frames)
     }
 
+    //Companion frames get the same flows applied as the input frames, so they have to match them exactly
+    fn load_companion_frames(&mut self, filenames: &StringArray) -> Result<Frames, Error> {
+        let frames = self.load_separate_frames(filenames)?;
+
+        if frames.len() != self.input_frames.len() {
+            return Err(BitmapflowError::FrameCountMismatch {
+                expected: self.input_frames.len(),
+                found: frames.len(),
+            }
+            .into());
+        }
+
+        for (i, (frame, source_size)) in frames.iter().zip(&self.source_frame_sizes).enumerate() {
+            if frame.dimensions() != *source_size {
+                return Err(anyhow!(
+                    "frame {} has size {:?}, but the matching animation frame has size {:?}",
+                    i,
+                    frame.dimensions(),
+                    source_size
+                ));
+            }
+        }
+
+        let target = match self.input_frames.first() {
+            Some(first) => first.dimensions(),
+            None => return Err(BitmapflowError::NoFrames.into()),
+        };
+        Ok(frames
+            .into_iter()
+            .map(|frame| fit_to_size(frame, target, &self.source_frame_size_params))
+            .collect())
+    }
+
     fn load_spritesheet(&mut self, filename: &str, rects: &[Rect2]) -> Result<Frames, Error> {
         let img = ImageReader::open(filename.to_string())?.decode()?;
 
@@ -117,6 +255,75 @@ impl ImageHolder {
 
         Ok(new_input_frames)
     }
+
+    //Also returns the duration of every frame, if the atlas has them
+    fn load_atlas(&mut self, json_filename: &str) -> Result<(Frames, Vec<Option<u32>>), Error> {
+        let atlas = Atlas::load(json_filename)?;
+        let img = ImageReader::open(&atlas.image_filename)?.decode()?;
+
+        let durations_msec = atlas.frames.iter().map(|frame| frame.duration).collect();
+        Ok((atlas.extract_frames(&img)?, durations_msec))
+    }
+
+    //Every input frame is followed by the same amount of inbetweens, so the original frames
+    //are found at every (inbetweens + 1)th position in the output
+    pub fn is_keyframe(&self, output_index: usize) -> bool {
+        if self.input_frames.is_empty() {
+            return true;
+        }
+
+        let frames_per_keyframe = (self.output_frames.len() / self.input_frames.len()).max(1);
+        output_index % frames_per_keyframe == 0
+    }
+
+    //How long every output frame is shown, in milliseconds.
+    //Input frames with a stored duration spread it over their inbetweens, the rest use fps.
+    pub fn output_durations_msec(&self, fps: f64) -> Vec<u16> {
+        let delay_msec = (1000.0 / fps.max(0.01)).round().clamp(1.0, u16::MAX as f64) as u16;
+        if self.input_frames.is_empty() {
+            return vec![delay_msec; self.output_frames.len()];
+        }
+
+        let frames_per_keyframe = (self.output_frames.len() / self.input_frames.len()).max(1);
+        (0..self.output_frames.len())
+            .map(|i| {
+                let input_index = (i / frames_per_keyframe).min(self.input_frames.len() - 1);
+                match self
+                    .input_durations_msec
+                    .get(input_index)
+                    .copied()
+                    .flatten()
+                {
+                    Some(duration) => {
+                        //Hand out the remainder to the first frames so the total stays the same
+                        let n = frames_per_keyframe as u32;
+                        let j = (i % frames_per_keyframe) as u32;
+                        let share = duration / n + if j < duration % n { 1 } else { 0 };
+                        share.clamp(1, u16::MAX as u32) as u16
+                    }
+                    None => delay_msec,
+                }
+            })
+            .collect()
+    }
+
+    //Returns the (first, last) output frame index of every original frame plus its inbetweens
+    pub fn keyframe_segments(&self) -> Vec<(usize, usize)> {
+        let keyframe_indices: Vec<usize> = (0..self.output_frames.len())
+            .filter(|i| self.is_keyframe(*i))
+            .collect();
+
+        keyframe_indices
+            .iter()
+            .enumerate()
+            .map(|(segment_index, from)| {
+                let to = keyframe_indices
+                    .get(segment_index + 1)
+                    .map_or(self.output_frames.len() - 1, |next| next - 1);
+                (*from, to)
+            })
+            .collect()
+    }
 }
 
 #[methods]
@@ -147,14 +354,17 @@ impl ImageHolder {
 
     #[export]
     fn _on_ui_loaded_gif(&mut self, owner: TRef<'_, Base>, filename: String) {
-        match self.load_gif(&filename) {
+        match self
+            .load_gif(&filename)
+            .and_then(|frames| self.check_loaded_frames(owner, &filename, frames))
+        {
             Ok(frames) => {
                 self.output_frames.clear();
-                self.update_input_frames(owner, frames);
+                self.update_input_frames(owner, frames, vec![]);
                 info!("Loaded gif: {}", filename);
             }
             Err(err) => {
-                let err_str = format!("Failed to load gif called {}: {:?}", filename, err);
+                let err_str = format!("Failed to load gif called {}: {:#}", filename, err);
                 error!("{}", err_str);
                 owner.emit_signal("image_load_failure", &[Variant::from_str(err_str)]);
             }
@@ -163,15 +373,63 @@ impl ImageHolder {
 
     #[export]
     fn _on_ui_loaded_separate_frames(&mut self, owner: TRef<'_, Base>, filenames: StringArray) {
-        match self.load_separate_frames(&filenames) {
+        match self
+            .load_separate_frames(&filenames)
+            .and_then(|frames| self.check_loaded_frames(owner, "the selected files", frames))
+        {
             Ok(frames) => {
                 self.output_frames.clear();
-                self.update_input_frames(owner, frames);
+                self.update_input_frames(owner, frames, vec![]);
                 info!("Loaded separate frames: {:?}", filenames);
             }
             Err(err) => {
                 let err_str = format!(
-                    "Failed to load separate frames called {:?}: {:?}",
+                    "Failed to load separate frames called {:?}: {:#}",
+                    filenames, err
+                );
+                error!("{}", err_str);
+                owner.emit_signal("image_load_failure", &[Variant::from_str(err_str)]);
+            }
+        }
+    }
+
+    #[export]
+    fn _on_ui_loaded_companion_frames(
+        &mut self,
+        owner: TRef<'_, Base>,
+        name: String,
+        filenames: StringArray,
+    ) {
+        match self.load_companion_frames(&filenames) {
+            Ok(frames) => {
+                owner.emit_signal(
+                    "companion_frames_loaded",
+                    &[name.to_variant(), frames.to_variant()],
+                );
+                info!("Loaded {} frames: {:?}", name, filenames);
+            }
+            Err(err) => {
+                let err_str = format!(
+                    "Failed to load {} frames called {:?}: {:#}",
+                    name, filenames, err
+                );
+                error!("{}", err_str);
+                owner.emit_signal("image_load_failure", &[Variant::from_str(err_str)]);
+            }
+        }
+    }
+
+    //Region masks are loaded like companion frames, but control the warping instead of being warped
+    #[export]
+    fn _on_ui_loaded_region_masks(&mut self, owner: TRef<'_, Base>, filenames: StringArray) {
+        match self.load_companion_frames(&filenames) {
+            Ok(masks) => {
+                owner.emit_signal("region_masks_loaded", &[masks.to_variant()]);
+                info!("Loaded region masks: {:?}", filenames);
+            }
+            Err(err) => {
+                let err_str = format!(
+                    "Failed to load region masks called {:?}: {:#}",
                     filenames, err
                 );
                 error!("{}", err_str);
@@ -187,14 +445,41 @@ impl ImageHolder {
         filename: String,
         rects: Vec<Rect2>,
     ) {
-        match self.load_spritesheet(&filename, &rects) {
+        match self
+            .load_spritesheet(&filename, &rects)
+            .and_then(|frames| self.check_loaded_frames(owner, &filename, frames))
+        {
             Ok(frames) => {
                 self.output_frames.clear();
-                self.update_input_frames(owner, frames);
+                self.update_input_frames(owner, frames, vec![]);
                 info!("Loaded spritesheet: {}", filename);
             }
             Err(err) => {
-                let err_str = format!("Failed to load spritesheet called {}: {:?}", filename, err);
+                let err_str = format!("Failed to load spritesheet called {}: {:#}", filename, err);
+                error!("{}", err_str);
+                owner.emit_signal("image_load_failure", &[Variant::from_str(err_str)]);
+            }
+        }
+    }
+
+    #[export]
+    fn _on_ui_loaded_atlas(&mut self, owner: TRef<'_, Base>, json_filename: String) {
+        match self
+            .load_atlas(&json_filename)
+            .and_then(|(frames, durations_msec)| {
+                let frames = self.check_loaded_frames(owner, &json_filename, frames)?;
+                Ok((frames, durations_msec))
+            }) {
+            Ok((frames, durations_msec)) => {
+                self.output_frames.clear();
+                self.update_input_frames(owner, frames, durations_msec);
+                info!("Loaded atlas: {}", json_filename);
+            }
+            Err(err) => {
+                let err_str = format!(
+                    "Failed to load atlas metadata called {}: {:#}",
+                    json_filename, err
+                );
                 error!("{}", err_str);
                 owner.emit_signal("image_load_failure", &[Variant::from_str(err_str)]);
             }
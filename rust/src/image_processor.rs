@@ -1,23 +1,46 @@
 use std::{
-    fmt, panic,
-    sync::{Arc, Mutex, TryLockError},
+    any::Any,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, TryLockError,
+    },
     thread,
 };
 
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use gdnative::prelude::*;
-use image::RgbaImage;
-use itertools::{Either, Itertools};
+use image::{Rgba, RgbaImage};
 use log::*;
 use opencv::{
-    core::{Mat, MatTraitManual, Size2i, Vec2f, CV_32FC2},
+    core::{
+        copy_make_border, Mat, MatTraitManual, Rect, Scalar, Size2i, Vec2f, BORDER_WRAP, CV_32FC2,
+    },
     optflow::{self, InterpolationType, RLOFOpticalFlowParameter},
     prelude::*,
 };
 
 use crate::{
-    datatypes::{FlowAlg, FlowAlg::*, ImgParams},
-    frame::{flow_mat_to_frame, Frame, Frames},
+    autotune::autotune,
+    correspondence::{correct_flow, interpolate_flow},
+    datatypes::{
+        Correspondence, CorrespondenceMode, EdgeMode, FlowAlg, FlowAlg::*, GlobalMotion, HoleFill,
+        ImgParams, MotionVectorMode,
+    },
+    error::{check_frame_sizes, BitmapflowError, BitmapflowResult},
+    flow::FlowField,
+    flow_refinement::refine_flow,
+    flow_visualization::{draw_color_wheel_legend, flow_to_arrow_frame, flow_to_color_frame},
+    frame::{CompanionFrames, Frame, Frames},
+    global_motion::calc_flow_with_global_motion,
+    hole_filling::{fill_holes, find_holes},
+    quality::{evaluate_leave_one_out, QualityReport},
+    segmentation::{
+        apply_object_flows, calc_object_flows, object_flows_to_field, refine_object_flows,
+    },
+    stabilization::{estimate_offsets, shift_frame, stabilize_flow, unstabilize_flow},
+    temporal::{incoming_motion, quadratic_displacement, smooth_flows_temporally},
     utility::*,
 };
 
@@ -31,6 +54,9 @@ type MaybeError = Option<String>;
 pub enum ImageProcessorMessage {
     UpdatedInputImage(Frames),
     UpdatedImgParams(ImgParams),
+    UpdatedFlowOverrides(Vec<FlowField>),
+    UpdatedCompanionFrames(CompanionFrames),
+    UpdatedRegionMasks(Frames),
 }
 
 impl fmt::Debug for ImageProcessorMessage {
@@ -38,6 +64,13 @@ impl fmt::Debug for ImageProcessorMessage {
         match *self {
             ImageProcessorMessage::UpdatedInputImage(_) => write!(formatter, "UpdatedInputImage"),
             ImageProcessorMessage::UpdatedImgParams(_) => write!(formatter, "UpdatedImgParams"),
+            ImageProcessorMessage::UpdatedFlowOverrides(_) => {
+                write!(formatter, "UpdatedFlowOverrides")
+            }
+            ImageProcessorMessage::UpdatedCompanionFrames(_) => {
+                write!(formatter, "UpdatedCompanionFrames")
+            }
+            ImageProcessorMessage::UpdatedRegionMasks(_) => write!(formatter, "UpdatedRegionMasks"),
         }
     }
 }
@@ -46,6 +79,53 @@ impl fmt::Debug for ImageProcessorMessage {
 struct ImageProcessorInner {
     input_frames: Frames,
     img_params: ImgParams,
+    //Imported flow fields, used instead of computing the flow for the matching frame pair
+    flow_overrides: Vec<FlowField>,
+    //Warped with the same flows as the colour frames
+    companion_frames: CompanionFrames,
+    //One per input frame, see RegionMask
+    region_masks: Frames,
+}
+
+#[derive(Default)]
+struct LatestMessages {
+    img_params: Option<ImgParams>,
+    input_frames: Option<Frames>,
+    flow_overrides: Option<Vec<FlowField>>,
+    companion_frames: Option<CompanionFrames>,
+    region_masks: Option<Frames>,
+}
+
+//Per pixel override of the warping, painted in a mask image.
+//Red freezes pixels in place, green cross-fades them to frame_b instead of moving them.
+pub struct RegionMask<'a> {
+    pub mask: &'a Frame,
+    pub frame_b: &'a Frame,
+    //How far the output is between the warped frame and frame_b, used for cross-fading
+    pub t: f32,
+}
+
+impl RegionMask<'_> {
+    pub fn blend(&self, x: u32, y: u32, warped: Rgba<u8>, original: Rgba<u8>) -> Rgba<u8> {
+        let [r, g, _b, a] = self.mask[(x, y)].0;
+        let alpha = a as f32 / 255.0;
+        let freeze = r as f32 / 255.0 * alpha;
+        let crossfade = (g as f32 / 255.0 * alpha).min(1.0 - freeze);
+        if freeze == 0.0 && crossfade == 0.0 {
+            return warped;
+        }
+
+        let target = self.frame_b[(x, y)];
+        let mut blended = [0; 4];
+        for c in 0..4 {
+            let faded = original[c] as f32 * (1.0 - self.t) + target[c] as f32 * self.t;
+            blended[c] = (warped[c] as f32 * (1.0 - freeze - crossfade)
+                + original[c] as f32 * freeze
+                + faded * crossfade)
+                .round() as u8;
+        }
+        Rgba(blended)
+    }
 }
 
 #[derive(NativeClass)]
@@ -60,6 +140,21 @@ pub struct ImageProcessor {
     )>,
     progress_channel: Arc<(Sender<f64>, Receiver<f64>)>,
     inner: Arc<Mutex<ImageProcessorInner>>,
+    //The flow between every pair of input frames, as computed in the last finished run
+    output_flows: Arc<Mutex<Vec<FlowField>>>,
+    //The companion frames of the last finished run, warped exactly like the output frames
+    output_companion_frames: Arc<Mutex<CompanionFrames>>,
+    quality_channel: Arc<(Sender<QualityReport>, Receiver<QualityReport>)>,
+    autotune_channel: Arc<(
+        Sender<(ImgParams, QualityReport)>,
+        Receiver<(ImgParams, QualityReport)>,
+    )>,
+    //Set to stop the running auto-tune
+    autotune_cancelled: Arc<AtomicBool>,
+    //Set while a quality report or auto-tune runs, they share the progress bar so only one may run at a time
+    evaluation_running: Arc<AtomicBool>,
+    //Companion frames loaded for the current input frames, kept here so they can be updated one set at a time
+    companion_frames: CompanionFrames,
 }
 
 impl ImageProcessor {
@@ -70,6 +165,13 @@ impl ImageProcessor {
             update_channel: Arc::new(unbounded()),
             progress_channel: Arc::new(unbounded()),
             inner: Default::default(),
+            output_flows: Default::default(),
+            output_companion_frames: Default::default(),
+            quality_channel: Arc::new(unbounded()),
+            autotune_channel: Arc::new(unbounded()),
+            autotune_cancelled: Default::default(),
+            evaluation_running: Default::default(),
+            companion_frames: Default::default(),
         }
     }
 
@@ -92,6 +194,32 @@ impl ImageProcessor {
                 usage: PropertyUsage::DEFAULT,
             }],
         });
+        builder.add_signal(Signal {
+            name: "quality_evaluated",
+            args: &[SignalArgument {
+                name: "report",
+                default: Variant::from_dictionary(&Dictionary::new_shared()),
+                export_info: ExportInfo::new(VariantType::Dictionary),
+                usage: PropertyUsage::DEFAULT,
+            }],
+        });
+        builder.add_signal(Signal {
+            name: "autotune_finished",
+            args: &[
+                SignalArgument {
+                    name: "img_params",
+                    default: Variant::from_dictionary(&Dictionary::new_shared()),
+                    export_info: ExportInfo::new(VariantType::Dictionary),
+                    usage: PropertyUsage::DEFAULT,
+                },
+                SignalArgument {
+                    name: "report",
+                    default: Variant::from_dictionary(&Dictionary::new_shared()),
+                    export_info: ExportInfo::new(VariantType::Dictionary),
+                    usage: PropertyUsage::DEFAULT,
+                },
+            ],
+        });
         builder.add_signal(Signal {
             name: "error_occured",
             args: &[SignalArgument {
@@ -108,6 +236,8 @@ impl ImageProcessor {
         let error_sender = Arc::clone(&self.error_channel).0.clone();
         let progress_sender = Arc::clone(&self.progress_channel).0.clone();
         let inner = Arc::clone(&self.inner);
+        let output_flows = Arc::clone(&self.output_flows);
+        let output_companion_frames = Arc::clone(&self.output_companion_frames);
 
         let update_channel = Arc::clone(&self.update_channel);
         let update_receiver = update_channel.1.clone();
@@ -117,7 +247,7 @@ impl ImageProcessor {
             let update_receiver = update_channel.1.clone();
 
             loop {
-                let (new_params, new_frames) = wait_and_retain_latest_messages(&update_receiver);
+                let latest = wait_and_retain_latest_messages(&update_receiver);
 
                 let lock = inner.lock();
                 let mut inner = match lock {
@@ -128,36 +258,123 @@ impl ImageProcessor {
                     }
                 };
 
-                if let Some(params) = new_params {
+                if let Some(params) = latest.img_params {
                     inner.img_params = params;
                 }
 
-                if let Some(img) = new_frames {
+                if let Some(img) = latest.input_frames {
                     inner.input_frames = img;
+                    //Imported flows and companion frames belong to the old frames
+                    inner.flow_overrides.clear();
+                    inner.companion_frames.clear();
+                    inner.region_masks.clear();
+                }
+
+                if let Some(flow_overrides) = latest.flow_overrides {
+                    inner.flow_overrides = flow_overrides;
+                }
+
+                //Problems with the loaded data, reported once this run has cleared the previous error
+                let mut warnings: Vec<String> = vec![];
+
+                if let Some(region_masks) = latest.region_masks {
+                    if region_masks.len() == inner.input_frames.len() {
+                        inner.region_masks = region_masks;
+                    } else {
+                        let err = BitmapflowError::RegionMaskCountMismatch {
+                            expected: inner.input_frames.len(),
+                            found: region_masks.len(),
+                        };
+                        warn!("{}", err);
+                        warnings.push(err.to_string());
+                    }
                 }
 
-                let thread_result = panic::catch_unwind(|| {
-                    let mut input_frames: Vec<&Frame> = inner.input_frames.iter().collect();
-                    //NOTE: input_frames contains references to inner.input_frames
+                if let Some(companion_frames) = latest.companion_frames {
+                    let frame_count = inner.input_frames.len();
+                    let (matching, skipped): (CompanionFrames, CompanionFrames) = companion_frames
+                        .into_iter()
+                        .partition(|(_name, frames)| frames.len() == frame_count);
+                    inner.companion_frames = matching;
 
-                    let mut frame_count = input_frames.len();
-                    if frame_count == 0 {
+                    for (name, frames) in skipped {
+                        let err = BitmapflowError::CompanionCountMismatch {
+                            name,
+                            expected: frame_count,
+                            found: frames.len(),
+                        };
+                        warn!("{}", err);
+                        warnings.push(err.to_string());
+                    }
+                }
+
+                let thread_result = panic::catch_unwind(|| -> BitmapflowResult<()> {
+                    if inner.input_frames.is_empty() {
                         //Haven't received any frames yet, retrying......
-                        return;
+                        return Ok(());
                     }
 
+                    check_frame_sizes(&inner.input_frames)?;
+
                     let ImgParams {
                         mut inbetweens,
                         loop_seamlessly,
                         flow_multiplier: total_flow_multiplier,
                         optflow_alg,
                         show_motion_vectors,
+                        motion_vector_mode,
+                        motion_vector_scale,
+                        motion_vector_legend,
+                        tileable,
+                        mut edge_mode,
+                        stabilization,
+                        reapply_offsets,
+                        global_motion,
+                        segment_objects,
+                        correspondences,
+                        correspondence_mode,
+                        correspondence_radius,
+                        flow_refinement,
+                        temporal_smoothing,
+                        quadratic_motion,
+                        hole_fill,
                     } = inner.img_params.clone();
 
                     if show_motion_vectors {
                         inbetweens = 1;
                     }
 
+                    //Anything but wrapping would break the seams of a tileable texture
+                    if tileable {
+                        edge_mode = EdgeMode::Wrap;
+                    }
+
+                    //Pixels uncovered by moving a frame are transparent, unless the frame tiles
+                    let shift_edge_mode = if tileable {
+                        EdgeMode::Wrap
+                    } else {
+                        EdgeMode::Transparent
+                    };
+
+                    let mut offsets = estimate_offsets(&inner.input_frames, stabilization)?;
+                    let stabilize = |frames: &Frames| -> Frames {
+                        frames
+                            .iter()
+                            .zip(&offsets)
+                            .map(|(frame, (x, y))| shift_frame(frame, (-x, -y), shift_edge_mode))
+                            .collect()
+                    };
+                    let stabilized_frames = stabilize(&inner.input_frames);
+                    let stabilized_companions: CompanionFrames = inner
+                        .companion_frames
+                        .iter()
+                        .map(|(name, frames)| (name.clone(), stabilize(frames)))
+                        .collect();
+                    let stabilized_masks = stabilize(&inner.region_masks);
+
+                    let mut input_frames: Vec<&Frame> = stabilized_frames.iter().collect();
+                    //NOTE: input_frames contains references to stabilized_frames
+
                     let mut flow_multipliers = vec![];
                     for i in 0..(inbetweens + 1) {
                         flow_multipliers.push(i as f32 / (inbetweens + 1) as f32);
@@ -166,102 +383,408 @@ impl ImageProcessor {
                     //Copy the first frame reference to the end of the vector so it loops
                     if loop_seamlessly {
                         input_frames.push(input_frames.first().unwrap());
-                        frame_count = input_frames.len();
+                        offsets.push(offsets[0]);
                     } else {
                         //Else duplicate the last frame reference
                         input_frames.push(input_frames.last().unwrap());
-                        frame_count = input_frames.len();
+                        offsets.push(*offsets.last().unwrap());
                     }
+                    let frame_count = input_frames.len();
 
                     let mut output_frames: Frames = vec![];
+                    let mut output_companions: CompanionFrames = stabilized_companions
+                        .iter()
+                        .map(|(name, _frames)| (name.clone(), vec![]))
+                        .collect();
+                    let mut flows: Vec<FlowField> = vec![];
                     progress_sender.send(f64::EPSILON).unwrap();
                     error_sender.send(None).unwrap(); //Clear previous error
+                    for warning in &warnings {
+                        error_sender.send(Some(warning.clone())).unwrap();
+                    }
+                    if segment_objects && (temporal_smoothing > 0.0 || quadratic_motion) {
+                        let warning = BitmapflowError::TemporalWithSegmentation.to_string();
+                        error_sender.send(Some(warning)).unwrap();
+                    }
 
+                    //Every flow is calculated first, so they can be smoothed over time before warping
+                    let total_steps = if show_motion_vectors {
+                        frame_count - 1
+                    } else {
+                        (frame_count - 1) * (1 + flow_multipliers.len())
+                    };
                     let mut i = 0;
-                    for (frame_a, frame_b) in input_frames.windows(2).map(array_to_pair) {
+                    let mut pair_object_flows = vec![];
+                    //Flows that have to stay exactly as they are
+                    let mut fixed_flows = vec![];
+                    for (pair_index, (frame_a, frame_b)) in
+                        input_frames.windows(2).map(array_to_pair).enumerate()
+                    {
                         if has_pending_messages() {
                             //Cancelling current calculation
-                            return;
+                            return Ok(());
                         }
 
                         //Note that this throws away alpha information
                         let mat_a_bgr = Mat::from(*frame_a);
                         let mat_b_bgr = Mat::from(*frame_b);
 
-                        assert!(mat_a_bgr.depth().unwrap() == opencv::core::CV_8U);
-                        assert!(mat_b_bgr.depth().unwrap() == opencv::core::CV_8U);
-                        assert!(mat_a_bgr.channels().unwrap() == 3);
-                        assert!(mat_b_bgr.channels().unwrap() == 3);
-                        assert!(
-                            mat_a_bgr.size().unwrap() == mat_b_bgr.size().unwrap(),
-                            "Frames don't have the same size: {:?} != {:?}",
-                            mat_a_bgr.size().unwrap(),
-                            mat_b_bgr.size().unwrap()
+                        //Imported flows describe the original frames, like the correspondence points
+                        let flow_override = inner
+                            .flow_overrides
+                            .get(pair_index)
+                            .filter(|field| {
+                                field.width == frame_a.width() as usize
+                                    && field.height == frame_a.height() as usize
+                            })
+                            .map(|field| {
+                                stabilize_flow(
+                                    field,
+                                    offsets[pair_index],
+                                    offsets[pair_index + 1],
+                                    shift_edge_mode,
+                                )
+                            });
+
+                        //The points were placed on the original frames, so they have to be stabilized too
+                        let points: Option<Vec<Correspondence>> = correspondences
+                            .get(pair_index)
+                            .filter(|points| !points.is_empty())
+                            .map(|points| {
+                                let (offset_a, offset_b) =
+                                    (offsets[pair_index], offsets[pair_index + 1]);
+                                points
+                                    .iter()
+                                    .map(|point| Correspondence {
+                                        from: [
+                                            point.from[0] - offset_a.0 as f32,
+                                            point.from[1] - offset_a.1 as f32,
+                                        ],
+                                        to: [
+                                            point.to[0] - offset_b.0 as f32,
+                                            point.to[1] - offset_b.1 as f32,
+                                        ],
+                                    })
+                                    .collect()
+                            });
+
+                        //Imported flows and correspondence points always cover the whole frame
+                        let object_flows =
+                            if segment_objects && flow_override.is_none() && points.is_none() {
+                                let mut object_flows = calc_object_flows(
+                                    frame_a,
+                                    frame_b,
+                                    &optflow_alg,
+                                    global_motion,
+                                    tileable,
+                                    edge_mode,
+                                )?;
+                                refine_object_flows(&mut object_flows, frame_a, &flow_refinement)?;
+                                Some(object_flows)
+                            } else {
+                                None
+                            };
+
+                        let interpolate_points =
+                            correspondence_mode == CorrespondenceMode::Interpolate;
+
+                        let mut flow = if let Some(field) = &flow_override {
+                            Mat::from(field)
+                        } else if let (Some(points), true) = (&points, interpolate_points) {
+                            interpolate_flow(points, mat_a_bgr.size()?)?
+                        } else if let Some(object_flows) = &object_flows {
+                            Mat::from(&object_flows_to_field(
+                                object_flows,
+                                frame_a.width(),
+                                frame_a.height(),
+                            ))
+                        } else {
+                            Self::calc_flow(
+                                &mat_a_bgr,
+                                &mat_b_bgr,
+                                &optflow_alg,
+                                tileable,
+                                global_motion,
+                            )?
+                        };
+
+                        //Imported flows and flows made from correspondence points are used as they are.
+                        //Object flows were refined one by one, as those are what the warp uses.
+                        if flow_override.is_none()
+                            && !(points.is_some() && interpolate_points)
+                            && object_flows.is_none()
+                        {
+                            refine_flow(&mut flow, &mat_a_bgr, &flow_refinement)?;
+                        }
+
+                        if let (Some(points), false) = (&points, interpolate_points) {
+                            correct_flow(&mut flow, points, correspondence_radius)?;
+                        }
+
+                        flows.push(FlowField::from(&flow));
+                        fixed_flows.push(
+                            flow_override.is_some()
+                                || (points.is_some() && interpolate_points)
+                                || object_flows.is_some(),
                         );
-                        let mut flow =
-                            unsafe { Mat::new_size(mat_a_bgr.size().unwrap(), CV_32FC2).unwrap() };
+                        pair_object_flows.push(object_flows);
 
-                        match Self::do_optical_flow(&mat_a_bgr, &mat_b_bgr, &mut flow, &optflow_alg)
+                        i += 1;
+                        progress_sender.send(i as f64 / total_steps as f64).unwrap();
+                    }
+
+                    //Imported and interpolated flows are used as they are,
+                    //and segmented pairs are warped with their object flows, which this can't change
+                    if temporal_smoothing > 0.0 {
+                        let smoothed =
+                            smooth_flows_temporally(&flows, temporal_smoothing, loop_seamlessly);
+                        flows = flows
+                            .into_iter()
+                            .zip(smoothed)
+                            .zip(&fixed_flows)
+                            .map(|((flow, smoothed), fixed)| if *fixed { flow } else { smoothed })
+                            .collect();
+                    }
+
+                    let incoming: Vec<Option<FlowField>> = (0..flows.len())
+                        .map(|pair_index| {
+                            if quadratic_motion && pair_object_flows[pair_index].is_none() {
+                                incoming_motion(&flows, pair_index, loop_seamlessly)
+                            } else {
+                                None
+                            }
+                        })
+                        .collect();
+
+                    //Motion vectors are rendered after this, once the largest flow is known
+                    if !show_motion_vectors {
+                        for (pair_index, (frame_a, frame_b)) in
+                            input_frames.windows(2).map(array_to_pair).enumerate()
                         {
-                            Ok(()) => {}
-                            Err(err) => {
-                                warn!("Optical flow failed: {:?}", err);
-                                error_sender.send(Some(err.to_string())).unwrap();
-                                return;
+                            if has_pending_messages() {
+                                //Cancelling current calculation
+                                return Ok(());
                             }
-                        }
 
-                        if show_motion_vectors {
-                            output_frames.push(flow_mat_to_frame(&flow));
-                            i += 1;
-                            progress_sender
-                                .send(i as f64 / (frame_count - 1) as f64)
-                                .unwrap();
-                        } else {
-                            for flow_multiplier in &flow_multipliers {
-                                let flow_multiplier = flow_multiplier * total_flow_multiplier;
+                            let flow = Mat::from(&flows[pair_index]);
+
+                            for t in &flow_multipliers {
+                                let flow_multiplier = t * total_flow_multiplier;
 
                                 i += 1;
-                                progress_sender
-                                    .send(
-                                        i as f64
-                                            / ((frame_count - 1) * flow_multipliers.len()) as f64,
+                                progress_sender.send(i as f64 / total_steps as f64).unwrap();
+
+                                //Already scaled by t, so only the total multiplier is applied to it
+                                let quadratic_field =
+                                    incoming[pair_index].as_ref().map(|incoming| {
+                                        quadratic_displacement(&flows[pair_index], incoming, *t)
+                                    });
+                                let quadratic_flow = quadratic_field.as_ref().map(Mat::from);
+
+                                //The jitter removed by stabilizing, interpolated between the two frames
+                                let (offset_a, offset_b) =
+                                    (offsets[pair_index], offsets[pair_index + 1]);
+                                let offset = if reapply_offsets {
+                                    (
+                                        (offset_a.0 as f32 + (offset_b.0 - offset_a.0) as f32 * t)
+                                            .round() as i32,
+                                        (offset_a.1 as f32 + (offset_b.1 - offset_a.1) as f32 * t)
+                                            .round() as i32,
                                     )
-                                    .unwrap();
+                                } else {
+                                    (0, 0)
+                                };
 
-                                //Skip flow calc if no flow
-                                let output_buffer: Frame = if abs_diff_eq!(flow_multiplier, 0.0) {
-                                    (*frame_a).clone()
+                                //Input frame index of frame_b, for looking up the matching companion frame
+                                let next_index = if pair_index + 1 < stabilized_frames.len() {
+                                    pair_index + 1
+                                } else if loop_seamlessly {
+                                    0
+                                } else {
+                                    pair_index
+                                };
+
+                                //Objects are composited separately, they leave transparent holes already
+                                let holes = if hole_fill != HoleFill::None
+                                    && !abs_diff_eq!(flow_multiplier, 0.0)
+                                    && pair_object_flows[pair_index].is_none()
+                                {
+                                    //Found from the displacement that's actually applied
+                                    let mut holes = match &quadratic_field {
+                                        Some(field) => find_holes(field, total_flow_multiplier),
+                                        None => find_holes(&flows[pair_index], flow_multiplier),
+                                    };
+                                    //Pixels the region mask freezes or cross-fades aren't warped, so they have no holes
+                                    if let Some(mask) = stabilized_masks.get(pair_index) {
+                                        for (hole, pixel) in holes.iter_mut().zip(mask.pixels()) {
+                                            let [r, g, _b, a] = pixel.0;
+                                            if a > 0 && (r > 0 || g > 0) {
+                                                *hole = false;
+                                            }
+                                        }
+                                    }
+                                    Some(holes)
                                 } else {
-                                    Self::apply_flow_to(frame_a, &flow, flow_multiplier)
+                                    None
                                 };
-                                output_frames.push(output_buffer);
+
+                                //Skip flow calc if no flow
+                                let warp = |frame: &Frame, frame_b: &Frame| -> Frame {
+                                    let region_mask =
+                                        stabilized_masks.get(pair_index).map(|mask| RegionMask {
+                                            mask,
+                                            frame_b,
+                                            t: *t,
+                                        });
+
+                                    let mut warped = if abs_diff_eq!(flow_multiplier, 0.0) {
+                                        frame.clone()
+                                    } else if let Some(object_flows) =
+                                        &pair_object_flows[pair_index]
+                                    {
+                                        apply_object_flows(
+                                            frame,
+                                            frame_b,
+                                            object_flows,
+                                            flow_multiplier,
+                                            *t,
+                                            edge_mode,
+                                            region_mask.as_ref(),
+                                        )
+                                    } else if let Some(quadratic_flow) = &quadratic_flow {
+                                        Self::apply_flow_to(
+                                            frame,
+                                            quadratic_flow,
+                                            total_flow_multiplier,
+                                            edge_mode,
+                                            region_mask.as_ref(),
+                                        )
+                                    } else {
+                                        Self::apply_flow_to(
+                                            frame,
+                                            &flow,
+                                            flow_multiplier,
+                                            edge_mode,
+                                            region_mask.as_ref(),
+                                        )
+                                    };
+                                    if let Some(holes) = &holes {
+                                        fill_holes(
+                                            &mut warped,
+                                            holes,
+                                            hole_fill,
+                                            frame_b,
+                                            &flows[pair_index],
+                                            *t,
+                                        );
+                                    }
+                                    if offset == (0, 0) {
+                                        warped
+                                    } else {
+                                        shift_frame(&warped, offset, shift_edge_mode)
+                                    }
+                                };
+                                output_frames.push(warp(frame_a, frame_b));
+
+                                for ((_name, companion_input), (_, companion_output)) in
+                                    stabilized_companions.iter().zip(&mut output_companions)
+                                {
+                                    companion_output.push(warp(
+                                        &companion_input[pair_index],
+                                        &companion_input[next_index],
+                                    ));
+                                }
                             }
                         }
                     }
+
+                    if show_motion_vectors {
+                        let max_magnitude = if motion_vector_scale > 0.0 {
+                            motion_vector_scale
+                        } else {
+                            flows
+                                .iter()
+                                .map(FlowField::max_magnitude)
+                                .fold(0.0, f32::max)
+                        };
+
+                        output_frames = flows
+                            .iter()
+                            .zip(&input_frames)
+                            .map(|(flow, frame)| {
+                                let mut output = match motion_vector_mode {
+                                    MotionVectorMode::ColorWheel => {
+                                        flow_to_color_frame(flow, max_magnitude)
+                                    }
+                                    MotionVectorMode::Arrows => {
+                                        flow_to_arrow_frame(frame, flow, max_magnitude)
+                                    }
+                                };
+                                if motion_vector_legend {
+                                    draw_color_wheel_legend(&mut output);
+                                }
+                                output
+                            })
+                            .collect();
+                    }
+
                     progress_sender.send(1.0).unwrap(); //done
 
+                    if show_motion_vectors {
+                        //Motion vectors have nothing to do with the companion frames
+                        output_companions.clear();
+                    }
+
+                    //Exported flows describe the original frames, so the removed jitter goes back in
+                    let flows: Vec<FlowField> = flows
+                        .iter()
+                        .enumerate()
+                        .map(|(k, flow)| {
+                            unstabilize_flow(flow, offsets[k], offsets[k + 1], shift_edge_mode)
+                        })
+                        .collect();
+                    match output_flows.lock() {
+                        Ok(mut output_flows) => *output_flows = flows,
+                        Err(err) => *err.into_inner() = flows,
+                    }
+                    match output_companion_frames.lock() {
+                        Ok(mut output_companion_frames) => {
+                            *output_companion_frames = output_companions
+                        }
+                        Err(err) => *err.into_inner() = output_companions,
+                    }
                     output_img_sender.send(output_frames).unwrap();
+                    Ok(())
                 });
 
-                if let Err(err) = thread_result {
-                    let mut err_str =
-                        format!("Unknown error '{:?}' with type {:?}", err, err.type_id());
-
-                    if let Some(dynamic_string) = err.downcast_ref::<String>() {
-                        err_str = dynamic_string.clone();
-                    } else if let Some(static_string) = err.downcast_ref::<&str>() {
-                        err_str = static_string.to_string();
+                match thread_result {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => {
+                        warn!("Processing failed: {:?}", err);
+                        error_sender.send(Some(err.to_string())).unwrap();
+                    }
+                    Err(err) => {
+                        let err_str = panic_message(err);
+                        warn!("ImageProcessor thread had a panic attack: {:?}", err_str);
+                        error_sender.send(Some(err_str)).unwrap();
                     }
-
-                    warn!("ImageProcessor thread had a panic attack: {:?}", err_str);
-                    error_sender.send(Some(err_str)).unwrap();
                 }
             }
         })
     }
 }
 
+//Turns the payload of a caught panic into a message for the user
+fn panic_message(err: Box<dyn Any + Send>) -> String {
+    if let Some(dynamic_string) = err.downcast_ref::<String>() {
+        dynamic_string.clone()
+    } else if let Some(static_string) = err.downcast_ref::<&str>() {
+        static_string.to_string()
+    } else {
+        format!("Unknown error '{:?}' with type {:?}", err, err.type_id())
+    }
+}
+
 #[methods]
 impl ImageProcessor {
     #[export]
@@ -274,12 +797,231 @@ impl ImageProcessor {
 
     #[export]
     fn _on_imageholder_image_loaded(&mut self, _owner: TRef<'_, Base>, frames: Frames) {
+        self.companion_frames.clear();
         self.update_channel
             .0
             .send(ImageProcessorMessage::UpdatedInputImage(frames))
             .unwrap();
     }
 
+    #[export]
+    fn _on_imageholder_companion_frames_loaded(
+        &mut self,
+        _owner: TRef<'_, Base>,
+        name: String,
+        frames: Frames,
+    ) {
+        //Loading a set with the same name again replaces it
+        self.companion_frames
+            .retain(|(other_name, _)| *other_name != name);
+        self.companion_frames.push((name, frames));
+
+        self.update_channel
+            .0
+            .send(ImageProcessorMessage::UpdatedCompanionFrames(
+                self.companion_frames.clone(),
+            ))
+            .unwrap();
+    }
+
+    #[export]
+    fn _on_imageholder_region_masks_loaded(&mut self, _owner: TRef<'_, Base>, masks: Frames) {
+        self.update_channel
+            .0
+            .send(ImageProcessorMessage::UpdatedRegionMasks(masks))
+            .unwrap();
+    }
+
+    #[export]
+    fn _on_ui_loaded_flow_fields(&mut self, owner: TRef<'_, Base>, filenames: StringArray) {
+        let mut filenames: Vec<String> = filenames.read().iter().map(|f| f.to_string()).collect();
+        //Flow fields are matched to frame pairs by filename order
+        filenames.sort();
+
+        let mut flow_overrides = vec![];
+        for filename in &filenames {
+            match FlowField::read_flo(filename) {
+                Ok(field) => flow_overrides.push(field),
+                Err(err) => {
+                    warn!("Failed to load flow field {}: {:?}", filename, err);
+                    owner.emit_signal(
+                        "error_occured",
+                        &[format!("Failed to load flow field {}: {}", filename, err).to_variant()],
+                    );
+                    return;
+                }
+            }
+        }
+
+        if let Err(err) = Self::check_flow_overrides(&filenames, &flow_overrides) {
+            warn!("Rejected flow fields: {}", err);
+            owner.emit_signal("error_occured", &[err.to_string().to_variant()]);
+            return;
+        }
+
+        info!("Loaded {} flow fields", flow_overrides.len());
+        self.update_channel
+            .0
+            .send(ImageProcessorMessage::UpdatedFlowOverrides(flow_overrides))
+            .unwrap();
+    }
+
+    //Whether there's a flow field for every frame pair, and all of them are the size of the frames
+    fn check_flow_overrides(
+        filenames: &[String],
+        flow_overrides: &[FlowField],
+    ) -> BitmapflowResult<()> {
+        let frames =
+            do_with_image_holder(|imageholder, _owner| imageholder.input_frames.clone()).unwrap();
+        let expected = check_frame_sizes(&frames)?;
+
+        //Whether the last frame pair (last to first frame) exists depends on loop_seamlessly
+        let pairs = frames.len() - 1;
+        if flow_overrides.len() != pairs && flow_overrides.len() != pairs + 1 {
+            return Err(BitmapflowError::FlowFieldCountMismatch {
+                pairs,
+                found: flow_overrides.len(),
+            });
+        }
+
+        match filenames
+            .iter()
+            .zip(flow_overrides)
+            .find(|(_filename, field)| {
+                (field.width, field.height) != (expected.0 as usize, expected.1 as usize)
+            }) {
+            Some((filename, field)) => Err(BitmapflowError::FlowFieldSizeMismatch {
+                filename: filename.clone(),
+                size: (field.width, field.height),
+                expected,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    //Runs a leave-one-out evaluation of the input frames with the given params in the background
+    #[export]
+    fn _on_ui_requested_quality_report(&mut self, owner: TRef<'_, Base>, img_params: ImgParams) {
+        if !self.start_evaluation(owner) {
+            return;
+        }
+
+        let frames =
+            do_with_image_holder(|imageholder, _owner| imageholder.input_frames.clone()).unwrap();
+
+        let quality_sender = self.quality_channel.0.clone();
+        let error_sender = self.error_channel.0.clone();
+        let progress_sender = self.progress_channel.0.clone();
+        let running = Arc::clone(&self.evaluation_running);
+
+        thread::Builder::new()
+            .name("quality_thread".to_string())
+            .spawn(move || {
+                let progress = |progress: f64| {
+                    progress_sender.send(progress).unwrap();
+                    true
+                };
+
+                let thread_result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    evaluate_leave_one_out(&frames, &img_params, progress)
+                }));
+
+                match thread_result {
+                    Ok(Ok(Some(report))) => {
+                        info!(
+                            "Leave-one-out quality: PSNR {:.2}dB, SSIM {:.4}, alpha error {:.4}",
+                            report.mean_psnr, report.mean_ssim, report.mean_alpha_error
+                        );
+                        quality_sender.send(report).unwrap();
+                    }
+                    Ok(Ok(None)) => {}
+                    Ok(Err(err)) => {
+                        warn!("Quality evaluation failed: {:?}", err);
+                        error_sender
+                            .send(Some(format!("Quality evaluation failed: {}", err)))
+                            .unwrap();
+                    }
+                    Err(err) => {
+                        let err_str = panic_message(err);
+                        warn!("Quality thread had a panic attack: {:?}", err_str);
+                        error_sender
+                            .send(Some(format!("Quality evaluation failed: {}", err_str)))
+                            .unwrap();
+                    }
+                }
+
+                running.store(false, Ordering::SeqCst);
+            })
+            .expect("failed to start thread");
+    }
+
+    //Searches for the flow algorithm and parameters that best reconstruct held out input frames
+    #[export]
+    fn _on_ui_requested_autotune(&mut self, owner: TRef<'_, Base>, img_params: ImgParams) {
+        if !self.start_evaluation(owner) {
+            return;
+        }
+
+        let frames =
+            do_with_image_holder(|imageholder, _owner| imageholder.input_frames.clone()).unwrap();
+
+        self.autotune_cancelled.store(false, Ordering::Relaxed);
+        let cancelled = Arc::clone(&self.autotune_cancelled);
+
+        let autotune_sender = self.autotune_channel.0.clone();
+        let error_sender = self.error_channel.0.clone();
+        let progress_sender = self.progress_channel.0.clone();
+        let running = Arc::clone(&self.evaluation_running);
+
+        thread::Builder::new()
+            .name("autotune_thread".to_string())
+            .spawn(move || {
+                let progress = |progress: f64| progress_sender.send(progress).unwrap();
+
+                let thread_result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    autotune(&frames, &img_params, &cancelled, progress)
+                }));
+
+                match thread_result {
+                    Ok(Ok(Some(result))) => autotune_sender.send(result).unwrap(),
+                    Ok(Ok(None)) => info!("Auto-tune cancelled"),
+                    Ok(Err(err)) => {
+                        warn!("Auto-tune failed: {:?}", err);
+                        error_sender
+                            .send(Some(format!("Auto-tune failed: {}", err)))
+                            .unwrap();
+                    }
+                    Err(err) => {
+                        let err_str = panic_message(err);
+                        warn!("Auto-tune thread had a panic attack: {:?}", err_str);
+                        error_sender
+                            .send(Some(format!("Auto-tune failed: {}", err_str)))
+                            .unwrap();
+                    }
+                }
+
+                running.store(false, Ordering::SeqCst);
+            })
+            .expect("failed to start thread");
+    }
+
+    //Claims the evaluation slot, or tells the user another evaluation is still running
+    fn start_evaluation(&self, owner: TRef<'_, Base>) -> bool {
+        if self.evaluation_running.swap(true, Ordering::SeqCst) {
+            owner.emit_signal(
+                "error_occured",
+                &[BitmapflowError::EvaluationRunning.to_string().to_variant()],
+            );
+            return false;
+        }
+        true
+    }
+
+    #[export]
+    fn _on_ui_cancelled_autotune(&mut self, _owner: TRef<'_, Base>) {
+        self.autotune_cancelled.store(true, Ordering::Relaxed);
+    }
+
     #[export]
     fn _ready(&mut self, _owner: TRef<'_, Base>) {
         self.progress_channel.0.send(0.0).unwrap();
@@ -300,6 +1042,17 @@ impl ImageProcessor {
             owner.emit_signal("image_processed", &[frames.to_variant()]);
         }
 
+        if let Ok(report) = self.quality_channel.1.try_recv() {
+            owner.emit_signal("quality_evaluated", &[report.to_variant()]);
+        }
+
+        if let Ok((img_params, report)) = self.autotune_channel.1.try_recv() {
+            owner.emit_signal(
+                "autotune_finished",
+                &[img_params.to_variant(), report.to_variant()],
+            );
+        }
+
         if let Ok(maybe_err) = self.error_channel.1.try_recv() {
             owner.emit_signal(
                 "error_occured",
@@ -322,7 +1075,27 @@ impl ImageProcessor {
         is_busy
     }
 
-    fn apply_flow_to(frame: &Frame, flow: &Mat, flow_multiplier: f32) -> Frame {
+    pub fn get_output_companion_frames(&self) -> CompanionFrames {
+        match self.output_companion_frames.lock() {
+            Ok(output_companion_frames) => output_companion_frames.clone(),
+            Err(err) => err.into_inner().clone(),
+        }
+    }
+
+    pub fn get_output_flows(&self) -> Vec<FlowField> {
+        match self.output_flows.lock() {
+            Ok(output_flows) => output_flows.clone(),
+            Err(err) => err.into_inner().clone(),
+        }
+    }
+
+    pub fn apply_flow_to(
+        frame: &Frame,
+        flow: &Mat,
+        flow_multiplier: f32,
+        edge_mode: EdgeMode,
+        region_mask: Option<&RegionMask>,
+    ) -> Frame {
         let w = frame.width();
         let h = frame.height();
 
@@ -334,23 +1107,86 @@ impl ImageProcessor {
             }
 
             //TODO don't round if pixelmode = false, instead use bilinear filter
-            let new_x: i32 =
-                ((x as f32 - flow[0] * flow_multiplier).round() as i32).clamp(0, (w - 1) as i32);
-            let new_y: i32 =
-                ((y as f32 - flow[1] * flow_multiplier).round() as i32).clamp(0, (h - 1) as i32);
+            let new_x = (x as f32 - flow[0] * flow_multiplier).round() as i32;
+            let new_y = (y as f32 - flow[1] * flow_multiplier).round() as i32;
+
+            let warped = match (
+                edge_mode.apply(new_x, w as i32),
+                edge_mode.apply(new_y, h as i32),
+            ) {
+                (Some(new_x), Some(new_y)) => frame[(new_x as u32, new_y as u32)],
+                _ => Rgba([0, 0, 0, 0]),
+            };
 
-            frame[(new_x as u32, new_y as u32)]
+            match region_mask {
+                Some(region_mask) => region_mask.blend(x, y, warped, frame[(x, y)]),
+                None => warped,
+            }
         });
 
         Frame(inner)
     }
 
+    //Computes the flow from mat_a to mat_b. If tileable, the frames are surrounded by their own
+    //wrapped-around edges first, so the flow sees the neighbouring tiles instead of a hard border.
+    //With a global motion model, the optical flow algorithm only estimates what the model doesn't explain.
+    pub fn calc_flow(
+        mat_a: &Mat,
+        mat_b: &Mat,
+        optflow_alg: &FlowAlg,
+        tileable: bool,
+        global_motion: GlobalMotion,
+    ) -> BitmapflowResult<Mat> {
+        if global_motion != GlobalMotion::None {
+            return calc_flow_with_global_motion(mat_a, mat_b, global_motion, tileable, |a, b| {
+                Self::calc_flow(a, b, optflow_alg, tileable, GlobalMotion::None)
+            });
+        }
+
+        let size = mat_a.size()?;
+
+        if !tileable {
+            let mut flow = unsafe { Mat::new_size(size, CV_32FC2)? };
+            Self::do_optical_flow(mat_a, mat_b, &mut flow, optflow_alg)?;
+            return Ok(flow);
+        }
+
+        let (pad_x, pad_y) = ((size.width / 4).max(1), (size.height / 4).max(1));
+        let wrap_pad = |mat: &Mat| -> BitmapflowResult<Mat> {
+            let mut padded = Mat::default()?;
+            copy_make_border(
+                mat,
+                &mut padded,
+                pad_y,
+                pad_y,
+                pad_x,
+                pad_x,
+                BORDER_WRAP,
+                Scalar::default(),
+            )?;
+            Ok(padded)
+        };
+        let (padded_a, padded_b) = (wrap_pad(mat_a)?, wrap_pad(mat_b)?);
+
+        let mut padded_flow = unsafe { Mat::new_size(padded_a.size()?, CV_32FC2)? };
+        Self::do_optical_flow(&padded_a, &padded_b, &mut padded_flow, optflow_alg)?;
+
+        //Crop the padding off again
+        let roi = Mat::roi(
+            &padded_flow,
+            Rect::new(pad_x, pad_y, size.width, size.height),
+        )?;
+        let mut flow = Mat::default()?;
+        roi.copy_to(&mut flow)?;
+        Ok(flow)
+    }
+
     fn do_optical_flow(
         mat_a: &Mat,
         mat_b: &Mat,
         flow: &mut Mat,
         optflow_alg: &FlowAlg,
-    ) -> Result<(), opencv::Error> {
+    ) -> BitmapflowResult<()> {
         match *optflow_alg {
             SimpleFlow {
                 layers,
@@ -364,7 +1200,7 @@ impl ImageProcessor {
                 layers as i32,
                 averaging_block_size as i32,
                 max_flow as i32,
-            ),
+            )?,
             DenseRLOF {
                 forward_backward_threshold,
                 grid_step_x,
@@ -372,7 +1208,7 @@ impl ImageProcessor {
                 use_post_proc,
                 use_variational_refinement,
             } => {
-                let rlof_param = RLOFOpticalFlowParameter::create().unwrap();
+                let rlof_param = RLOFOpticalFlowParameter::create()?;
                 let grid_step = Size2i::new(grid_step_x, grid_step_y);
                 let interp_type = InterpolationType::INTERP_EPIC;
                 optflow::calc_optical_flow_dense_rlof(
@@ -392,18 +1228,18 @@ impl ImageProcessor {
                     500.0,
                     1.5,
                     use_variational_refinement,
-                )
-            }
-            ref x => {
-                panic!("Unknown optical flow algorithm {:?}", x);
+                )?
             }
+            ref x => return Err(BitmapflowError::InvalidFlowAlg(format!("{:?}", x))),
         }
+
+        Ok(())
     }
 }
 
 fn wait_and_retain_latest_messages(
     update_receiver: &Receiver<ImageProcessorMessage>,
-) -> (Option<ImgParams>, Option<Frames>) {
+) -> LatestMessages {
     //This method does 3 things:
     //1. Wait for a message to arrive (blocking)
     //2. Collect all pending messages in a list
@@ -413,13 +1249,28 @@ fn wait_and_retain_latest_messages(
     let mut msgs: Vec<ImageProcessorMessage> = update_receiver.try_iter().collect();
     msgs.insert(0, peeked);
 
-    let (mut msgs_params, mut msgs_frames): (Vec<_>, Vec<_>) =
-        msgs.into_iter().partition_map(|x| match x {
-            ImageProcessorMessage::UpdatedImgParams(params) => Either::Left(params),
-            ImageProcessorMessage::UpdatedInputImage(frames) => Either::Right(frames),
-        });
-
-    //TODO use hashmap instead?
+    let mut latest = LatestMessages::default();
+    for msg in msgs {
+        match msg {
+            ImageProcessorMessage::UpdatedImgParams(params) => latest.img_params = Some(params),
+            ImageProcessorMessage::UpdatedInputImage(frames) => {
+                latest.input_frames = Some(frames);
+                //Overrides and companions sent before these frames were meant for the old ones
+                latest.flow_overrides = None;
+                latest.companion_frames = None;
+                latest.region_masks = None;
+            }
+            ImageProcessorMessage::UpdatedFlowOverrides(flow_overrides) => {
+                latest.flow_overrides = Some(flow_overrides)
+            }
+            ImageProcessorMessage::UpdatedCompanionFrames(companion_frames) => {
+                latest.companion_frames = Some(companion_frames)
+            }
+            ImageProcessorMessage::UpdatedRegionMasks(region_masks) => {
+                latest.region_masks = Some(region_masks)
+            }
+        }
+    }
 
-    (msgs_params.pop(), msgs_frames.pop())
+    latest
 }
@@ -1,13 +1,25 @@
-use std::{fs::File, path::Path};
+use std::{fs, fs::File, path::Path};
 
 use anyhow::Result;
-use gdnative::{api::ImageTexture, prelude::*};
+use gdnative::prelude::*;
 use gif::{Encoder, Repeat};
-use image::{ImageBuffer, Rgba};
+use image::RgbaImage;
 use log::*;
 use rayon::prelude::*;
-
-use crate::{frame::texture_to_image, utility::do_with_image_holder};
+use serde_json::json;
+
+use crate::{
+    aseprite::{write_aseprite, AsepriteFrame, AsepriteTag},
+    atlas::{SpritesheetFrameInfo, SpritesheetInfo},
+    datatypes::{FlowMapParams, FlowMapPrecision, SpritesheetGenerationParams},
+    error::{check_frame_sizes, BitmapflowError},
+    flowmap::{encode_flow_16bit, encode_flow_8bit},
+    frame::{CompanionFrames, Frame, Frames},
+    godot_resource::{write_spriteframes, SpriteFramesSource},
+    image_holder::ImageHolder,
+    spritesheet_generator::{SpritesheetGenerator, SpritesheetLayout},
+    utility::{do_with_image_holder, do_with_image_processor},
+};
 
 type Base = Node;
 //Base refers to the type ImageSaver inherits from. In this case it's Node (because #[inherit(Node)])
@@ -48,40 +60,39 @@ impl ImageSaver {
 
         do_with_image_holder(|imageholder, _owner| {
             let frames = &imageholder.output_frames;
-            assert!(!frames.is_empty());
-
-            let width = frames[0].width();
-            let height = frames[1].height();
+            if frames.is_empty() {
+                return Err(BitmapflowError::NothingToSave.into());
+            }
 
-            for frame in frames {
-                assert!(
-                    frame.width() == width,
-                    "Frame width doesn't match: {} != {}",
-                    frame.width(),
-                    width
-                );
-                assert!(
-                    frame.height() == height,
-                    "Frame height doesn't match: {} != {}",
-                    frame.height(),
-                    height
-                );
+            let (width, height) = check_frame_sizes(frames)?;
+            if width > u16::MAX as u32 || height > u16::MAX as u32 {
+                return Err(BitmapflowError::ImageTooLarge {
+                    format: "GIF",
+                    size: (width, height),
+                    max: (u16::MAX as u32, u16::MAX as u32),
+                }
+                .into());
             }
 
-            let delay_msec = 1000.0 / fps.max(0.01);
-            let delay_csec = ((delay_msec / 10.0).ceil()).clamp(0.0, u16::MAX as f64) as u16;
-            //NOTE - as of 12-3-2021 there is STILL no safe way to convert a float to u16.
-            //If it doesn't fit you get UB. So there's that. https://github.com/rust-lang/rust/issues/10184
+            //GIF delays are in centiseconds
+            let delays_csec: Vec<u16> = imageholder
+                .output_durations_msec(fps)
+                .iter()
+                .map(|msec| ((*msec as u32 + 9) / 10) as u16)
+                .collect();
 
-            let mut encoder = Encoder::new(&mut image, width as u16, height as u16, &[])?;
-            encoder.set_repeat(Repeat::Infinite)?;
+            let mut encoder = Encoder::new(&mut image, width as u16, height as u16, &[])
+                .map_err(BitmapflowError::from)?;
+            encoder
+                .set_repeat(Repeat::Infinite)
+                .map_err(BitmapflowError::from)?;
 
-            info!("Saving gif with frame delay = {}csec...", delay_csec);
+            info!("Saving gif with {} frames...", frames.len());
 
             let gif_frames: Vec<_> = frames
                 .par_iter()
-                .enumerate()
-                .map(|(_i, frame)| {
+                .zip(&delays_csec)
+                .map(|(frame, delay_csec)| {
                     let mut raw_pixels = vec![];
 
                     for pixel in frame.pixels() {
@@ -93,7 +104,7 @@ impl ImageSaver {
                     let mut gif_frame =
                         gif::Frame::from_rgba(width as u16, height as u16, &mut raw_pixels);
 
-                    gif_frame.delay = delay_csec;
+                    gif_frame.delay = *delay_csec;
                     gif_frame.dispose = gif::DisposalMethod::Background;
 
                     gif_frame
@@ -101,7 +112,9 @@ impl ImageSaver {
                 .collect();
 
             for gif_frame in gif_frames {
-                encoder.write_frame(&gif_frame)?;
+                encoder
+                    .write_frame(&gif_frame)
+                    .map_err(BitmapflowError::from)?;
             }
 
             Ok(())
@@ -121,6 +134,27 @@ impl ImageSaver {
         }
     }
 
+    fn save_frames(frames: &[Frame], base_filename: &str) -> Result<Vec<String>> {
+        let results: Vec<Result<_>> = frames
+            .par_iter()
+            .enumerate()
+            .map(|(i, img)| {
+                let filename = format!("{}{:04}.png", base_filename, i);
+                img.save(filename.clone())?;
+
+                Ok(filename)
+            })
+            .collect();
+
+        let mut filenames = vec![];
+        for result in results {
+            let filename = result?;
+            filenames.push(filename);
+        }
+
+        Ok(filenames)
+    }
+
     fn save_separate_frames(&mut self, base_filename: &str) -> Result<Vec<String>> {
         let base_filename = base_filename.replace(".png", "");
 
@@ -129,23 +163,77 @@ impl ImageSaver {
 
         do_with_image_holder(|imageholder, _owner| {
             let frames = &imageholder.output_frames;
-            assert!(!frames.is_empty());
+            if frames.is_empty() {
+                return Err(BitmapflowError::NothingToSave.into());
+            }
 
-            let results: Vec<Result<_>> = frames
-                .par_iter()
-                .enumerate()
-                .map(|(i, img)| {
-                    let filename = format!("{}{:04}.png", base_filename, i);
-                    img.save(filename.clone())?;
+            Self::save_frames(frames, &base_filename)
+        })
+        .unwrap()
+    }
 
-                    Ok(filename)
-                })
-                .collect();
+    fn get_output_companion_frames() -> CompanionFrames {
+        do_with_image_processor(|imageprocessor, _owner| {
+            imageprocessor.get_output_companion_frames()
+        })
+        .unwrap()
+    }
+
+    //Companion files are saved next to the main file, e.g. sheet.png becomes sheet_normal.png
+    fn get_companion_filename(filename: &str, name: &str) -> String {
+        let path = Path::new(filename);
+        let stem = path.file_stem().unwrap().to_str().unwrap();
+        match path.extension() {
+            Some(ext) => {
+                path.with_file_name(format!("{}_{}.{}", stem, name, ext.to_str().unwrap()))
+            }
+            None => path.with_file_name(format!("{}_{}", stem, name)),
+        }
+        .to_string_lossy()
+        .to_string()
+    }
+
+    fn save_companion_separate_frames(&mut self, base_filename: &str) -> Result<Vec<String>> {
+        let base_filename = base_filename.replace(".png", "");
+
+        let mut filenames = vec![];
+        for (name, frames) in Self::get_output_companion_frames() {
+            filenames.extend(Self::save_frames(
+                &frames,
+                &format!("{}_{}", base_filename, name),
+            )?);
+        }
 
-            let mut filenames = vec![];
-            for result in results {
-                let filename = result?;
-                filenames.push(filename);
+        Ok(filenames)
+    }
+
+    fn save_aseprite(&mut self, filename: &str, fps: f64) -> Result<Vec<String>> {
+        let mut filenames = vec![filename.to_string()];
+        let companions: Vec<(String, Frames)> = Self::get_output_companion_frames()
+            .into_iter()
+            .map(|(name, frames)| (Self::get_companion_filename(filename, &name), frames))
+            .collect();
+
+        do_with_image_holder(|imageholder, _owner| {
+            if imageholder.output_frames.is_empty() {
+                return Err(BitmapflowError::NothingToSave.into());
+            }
+
+            let durations_msec = imageholder.output_durations_msec(fps);
+            Self::write_aseprite_file(
+                filename,
+                &imageholder.output_frames,
+                imageholder,
+                &durations_msec,
+            )?;
+            for (companion_filename, frames) in &companions {
+                Self::write_aseprite_file(
+                    companion_filename,
+                    frames,
+                    imageholder,
+                    &durations_msec,
+                )?;
+                filenames.push(companion_filename.clone());
             }
 
             Ok(filenames)
@@ -153,57 +241,471 @@ impl ImageSaver {
         .unwrap()
     }
 
+    //durations_msec has the duration of every frame
+    fn write_aseprite_file(
+        filename: &str,
+        frames: &[Frame],
+        imageholder: &ImageHolder,
+        durations_msec: &[u16],
+    ) -> Result<()> {
+        let file = File::create(filename)?;
+
+        let ase_frames: Vec<_> = frames
+            .iter()
+            .zip(durations_msec)
+            .enumerate()
+            .map(|(i, (frame, duration_msec))| AsepriteFrame {
+                frame,
+                duration_msec: *duration_msec,
+                is_keyframe: imageholder.is_keyframe(i),
+            })
+            .collect();
+
+        //One tag per original frame, spanning the frame itself and its inbetweens
+        let tags: Vec<_> = imageholder
+            .keyframe_segments()
+            .into_iter()
+            .enumerate()
+            .map(|(tag_index, (from, to))| AsepriteTag {
+                name: format!("Frame {}", tag_index + 1),
+                from: from as u16,
+                to: to as u16,
+            })
+            .collect();
+
+        info!(
+            "Saving aseprite file with {} frames and {} tags...",
+            ase_frames.len(),
+            tags.len()
+        );
+
+        write_aseprite(file, &ase_frames, &tags)
+    }
+
+    fn get_page_filename(filename: &str, page: usize, page_count: usize) -> String {
+        if page_count == 1 {
+            return filename.to_string();
+        }
+
+        //Multi-page spritesheets are saved as name_0.png, name_1.png, etc.
+        let path = Path::new(filename);
+        let stem = path.file_stem().unwrap().to_str().unwrap();
+        let extension = path.extension().map_or("png", |ext| ext.to_str().unwrap());
+        path.with_file_name(format!("{}_{}.{}", stem, page, extension))
+            .to_string_lossy()
+            .to_string()
+    }
+
     fn save_spritesheet(
         &mut self,
         filename: &str,
-        tex: Ref<ImageTexture, Shared>,
-    ) -> Result<String> {
-        let img: ImageBuffer<Rgba<u8>, Vec<u8>> = texture_to_image(tex);
-        img.save(filename.to_string())?;
+        spritesheet_params: &SpritesheetGenerationParams,
+        fps: f64,
+        loop_animation: bool,
+    ) -> Result<Vec<String>> {
+        let layout = SpritesheetGenerator::get_layout(spritesheet_params)?;
+        let page_count = layout.pages.len();
+
+        let page_filenames: Vec<String> = (0..page_count)
+            .map(|page| Self::get_page_filename(filename, page, page_count))
+            .collect();
+
+        for (page, page_filename) in page_filenames.iter().enumerate() {
+            let img = SpritesheetGenerator::render_page(spritesheet_params, &layout, page);
+            img.save(page_filename)?;
+        }
 
-        Ok(filename.to_string())
+        let mut filenames = page_filenames.clone();
+
+        //Companion sheets use the exact same layout, so they line up with the colour sheet
+        for (name, frames) in Self::get_output_companion_frames() {
+            let images: Vec<&RgbaImage> = frames.iter().map(|f| &f.0).collect();
+            for (page, page_filename) in page_filenames.iter().enumerate() {
+                let img = SpritesheetGenerator::render_images_page(
+                    &images,
+                    spritesheet_params,
+                    &layout,
+                    page,
+                );
+                let companion_filename = Self::get_companion_filename(page_filename, &name);
+                img.save(&companion_filename)?;
+                filenames.push(companion_filename);
+            }
+        }
+
+        if let Some(metadata_filename) = self.save_spritesheet_metadata(
+            filename,
+            &page_filenames,
+            &layout,
+            spritesheet_params,
+            fps,
+        )? {
+            filenames.push(metadata_filename);
+        }
+
+        if spritesheet_params.godot_spriteframes {
+            let tres_filename = Path::new(filename)
+                .with_extension("tres")
+                .to_string_lossy()
+                .to_string();
+
+            write_spriteframes(
+                &tres_filename,
+                SpriteFramesSource::Spritesheet {
+                    page_filenames: &page_filenames,
+                    frames: &layout.frames,
+                },
+                fps,
+                loop_animation,
+            )?;
+            filenames.push(tres_filename);
+        }
+
+        Ok(filenames)
+    }
+
+    fn save_spriteframes(
+        &mut self,
+        tres_filename: &str,
+        fps: f64,
+        loop_animation: bool,
+    ) -> Result<Vec<String>> {
+        let base_filename = Path::new(tres_filename).with_extension("");
+        let mut filenames = self.save_separate_frames(&base_filename.to_string_lossy())?;
+
+        write_spriteframes(
+            tres_filename,
+            SpriteFramesSource::SeparateFrames {
+                filenames: &filenames,
+            },
+            fps,
+            loop_animation,
+        )?;
+        filenames.insert(0, tres_filename.to_string());
+
+        Ok(filenames)
+    }
+
+    fn save_flowmap(
+        &mut self,
+        filename: &str,
+        flowmap_params: &FlowMapParams,
+        fps: f64,
+    ) -> Result<Vec<String>> {
+        let flows =
+            do_with_image_processor(|imageprocessor, _owner| imageprocessor.get_output_flows())
+                .unwrap();
+
+        do_with_image_holder(|imageholder, _owner| {
+            let frames = &imageholder.input_frames;
+
+            //There's one flow per input frame (the last one points to the first frame, or to itself if not looping)
+            if flows.is_empty() {
+                return Err(BitmapflowError::NoFlowsYet.into());
+            }
+            if flows.len() != frames.len() {
+                return Err(BitmapflowError::FlowsOutOfDate {
+                    flows: flows.len(),
+                    frames: frames.len(),
+                }
+                .into());
+            }
+
+            let (frame_width, frame_height) = check_frame_sizes(frames)?;
+            let frame_size = (frame_width as usize, frame_height as usize);
+            let frames_per_row = match flowmap_params.frames_per_row {
+                0 => (frames.len() as f64).sqrt().ceil() as usize,
+                frames_per_row => frames_per_row,
+            };
+            let range = match flowmap_params.range {
+                range if range > 0.0 => range,
+                _ => flows
+                    .iter()
+                    .map(|flow| flow.max_magnitude())
+                    .fold(1.0, f32::max),
+            };
+
+            //The color and flow spritesheets share the exact same layout
+            let spritesheet_params = SpritesheetGenerationParams {
+                frames_per_row,
+                ..Default::default()
+            };
+            let layout = SpritesheetGenerator::get_grid_layout_for(
+                &spritesheet_params,
+                frames.len(),
+                frame_size,
+            );
+
+            let path = Path::new(filename);
+            let stem = path.file_stem().unwrap().to_str().unwrap();
+            let flow_filename = path
+                .with_file_name(format!("{}_flow.png", stem))
+                .to_string_lossy()
+                .to_string();
+            let metadata_filename = path
+                .with_file_name(format!("{}_flow.json", stem))
+                .to_string_lossy()
+                .to_string();
+
+            let images: Vec<&RgbaImage> = frames.iter().map(|f| &f.0).collect();
+            SpritesheetGenerator::render_images_page(&images, &spritesheet_params, &layout, 0)
+                .save(filename)?;
+
+            match flowmap_params.precision {
+                FlowMapPrecision::Bit8 => {
+                    let encoded: Vec<_> =
+                        flows.iter().map(|f| encode_flow_8bit(f, range)).collect();
+                    let images: Vec<_> = encoded.iter().collect();
+                    SpritesheetGenerator::render_images_page(
+                        &images,
+                        &spritesheet_params,
+                        &layout,
+                        0,
+                    )
+                    .save(&flow_filename)?;
+                }
+                FlowMapPrecision::Bit16 => {
+                    let encoded: Vec<_> =
+                        flows.iter().map(|f| encode_flow_16bit(f, range)).collect();
+                    let images: Vec<_> = encoded.iter().collect();
+                    SpritesheetGenerator::render_images_page(
+                        &images,
+                        &spritesheet_params,
+                        &layout,
+                        0,
+                    )
+                    .save(&flow_filename)?;
+                }
+            }
+
+            let (sheet_w, sheet_h) = layout.pages[0];
+            let metadata = json!({
+                "color_image": path.file_name().unwrap().to_string_lossy(),
+                "flow_image": Path::new(&flow_filename).file_name().unwrap().to_string_lossy(),
+                "width": sheet_w,
+                "height": sheet_h,
+                "frame_width": frame_size.0,
+                "frame_height": frame_size.1,
+                "frame_count": frames.len(),
+                "frames_per_row": frames_per_row,
+                "fps": fps,
+                "precision": flowmap_params.precision,
+                //Decoding: flow_in_pixels = (rg * 2 - 1) * range
+                "range": range,
+                //Same as range, but relative to the size of a single frame, which is what shaders usually want
+                "uv_scale": [range / frame_size.0 as f32, range / frame_size.1 as f32],
+            });
+            fs::write(&metadata_filename, serde_json::to_string_pretty(&metadata)?)?;
+
+            Ok(vec![filename.to_string(), flow_filename, metadata_filename])
+        })
+        .unwrap()
+    }
+
+    //Saves the raw flow between every pair of input frames, as .flo (default) or .pfm files
+    fn save_flow_fields(&mut self, base_filename: &str) -> Result<Vec<String>> {
+        let extension = match Path::new(base_filename).extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("pfm") => "pfm",
+            _ => "flo",
+        };
+        let base_filename = base_filename
+            .trim_end_matches(".flo")
+            .trim_end_matches(".pfm");
+
+        let flows =
+            do_with_image_processor(|imageprocessor, _owner| imageprocessor.get_output_flows())
+                .unwrap();
+
+        if flows.is_empty() {
+            return Err(BitmapflowError::NoFlowsYet.into());
+        }
+
+        let results: Vec<Result<_>> = flows
+            .par_iter()
+            .enumerate()
+            .map(|(i, flow)| {
+                let filename = format!("{}{:04}.{}", base_filename, i, extension);
+                match extension {
+                    "pfm" => flow.write_pfm(&filename)?,
+                    _ => flow.write_flo(&filename)?,
+                }
+
+                Ok(filename)
+            })
+            .collect();
+
+        results.into_iter().collect()
+    }
+
+    fn save_spritesheet_metadata(
+        &mut self,
+        filename: &str,
+        page_filenames: &[String],
+        layout: &SpritesheetLayout,
+        spritesheet_params: &SpritesheetGenerationParams,
+        fps: f64,
+    ) -> Result<Option<String>> {
+        let json = do_with_image_holder(|imageholder, _owner| {
+            let durations_msec = imageholder.output_durations_msec(fps);
+            let info = SpritesheetInfo {
+                pages: page_filenames
+                    .iter()
+                    .zip(&layout.pages)
+                    .map(|(page_filename, size)| {
+                        let filename_only = Path::new(page_filename).file_name().unwrap();
+                        (filename_only.to_string_lossy().to_string(), *size)
+                    })
+                    .collect(),
+                fps,
+                frames: layout
+                    .frames
+                    .iter()
+                    .enumerate()
+                    .map(|(i, layout_frame)| SpritesheetFrameInfo {
+                        page: layout_frame.page,
+                        rect: layout_frame.rect,
+                        sprite_source_size: layout_frame.sprite_source_size,
+                        source_size: layout_frame.source_size,
+                        duration_msec: durations_msec.get(i).copied().unwrap_or(1) as u32,
+                        is_keyframe: imageholder.is_keyframe(i),
+                    })
+                    .collect(),
+                segments: imageholder.keyframe_segments(),
+            };
+
+            info.to_json(spritesheet_params.metadata_format)
+        })
+        .unwrap();
+
+        match json {
+            Some(json) => {
+                let metadata_filename = Path::new(filename)
+                    .with_extension("json")
+                    .to_string_lossy()
+                    .to_string();
+                fs::write(&metadata_filename, json)?;
+                Ok(Some(metadata_filename))
+            }
+            None => Ok(None),
+        }
     }
 }
 
 #[methods]
 impl ImageSaver {
+    //Tells the UI which files were written, by name only
+    fn emit_saved(owner: &Base, what: &str, filenames: &[String]) {
+        let strings = &filenames
+            .iter()
+            .map(|s| {
+                let filename_only = Path::new(&s).file_name().unwrap().to_str().unwrap();
+                GodotString::from(filename_only)
+            })
+            .collect();
+        info!("Saved {} succesfully: {:?}", what, filenames);
+        owner.emit_signal("image_save_success", &[Variant::from_string_array(strings)]);
+    }
+
     #[export]
     fn _on_ui_exported_gif(&mut self, owner: &Base, filename: String, fps: f64) {
         match self.save_gif(&filename, fps) {
-            Ok(()) => {
-                let filename_only = Path::new(&filename).file_name().unwrap().to_str().unwrap();
+            Ok(()) => Self::emit_saved(owner, "gif", &[filename.clone()]),
+            Err(err) => {
+                let err_str = format!("Failed to save gif as {}: {}", filename, err.to_string());
+                warn!("{}", err_str);
+                owner.emit_signal("image_save_failure", &[Variant::from_str(err_str)]);
+            }
+        };
+    }
 
-                info!("Saved gif succesfully: {:?}", filename);
+    #[export]
+    fn _on_ui_exported_aseprite(&mut self, owner: &Base, filename: String, fps: f64) {
+        match self.save_aseprite(&filename, fps) {
+            Ok(filenames) => Self::emit_saved(owner, "aseprite file", &filenames),
+            Err(err) => {
+                let err_str = format!(
+                    "Failed to save aseprite file as {}: {}",
+                    filename,
+                    err.to_string()
+                );
+                warn!("{}", err_str);
+                owner.emit_signal("image_save_failure", &[Variant::from_str(err_str)]);
+            }
+        };
+    }
 
-                owner.emit_signal(
-                    "image_save_success",
-                    &[Variant::from_string_array(&TypedArray::from_vec(vec![
-                        filename_only.into(),
-                    ]))],
-                )
+    #[export]
+    fn _on_ui_exported_spriteframes(
+        &mut self,
+        owner: &Base,
+        filename: String,
+        fps: f64,
+        loop_animation: bool,
+    ) {
+        match self.save_spriteframes(&filename, fps, loop_animation) {
+            Ok(filenames) => Self::emit_saved(owner, "SpriteFrames", &filenames),
+            Err(err) => {
+                let err_str = format!(
+                    "Failed to save SpriteFrames as {}: {}",
+                    filename,
+                    err.to_string()
+                );
+                warn!("{}", err_str);
+                owner.emit_signal("image_save_failure", &[Variant::from_str(err_str)]);
             }
+        };
+    }
+
+    #[export]
+    fn _on_ui_exported_flowmap(
+        &mut self,
+        owner: &Base,
+        filename: String,
+        flowmap_params: FlowMapParams,
+        fps: f64,
+    ) {
+        match self.save_flowmap(&filename, &flowmap_params, fps) {
+            Ok(filenames) => Self::emit_saved(owner, "flow map", &filenames),
             Err(err) => {
-                let err_str = format!("Failed to save gif as {}: {}", filename, err.to_string());
+                let err_str = format!(
+                    "Failed to save flow map as {}: {}",
+                    filename,
+                    err.to_string()
+                );
                 warn!("{}", err_str);
-                owner.emit_signal("image_save_failure", &[Variant::from_str(err_str)])
+                owner.emit_signal("image_save_failure", &[Variant::from_str(err_str)]);
             }
         };
     }
 
     #[export]
-    fn _on_ui_exported_separate_frames(&mut self, owner: &Base, base_filename: String) {
-        match self.save_separate_frames(&base_filename) {
-            Ok(filenames) => {
-                let strings = &filenames
-                    .iter()
-                    .map(|s| {
-                        let filename_only = Path::new(&s).file_name().unwrap().to_str().unwrap();
-                        GodotString::from(filename_only)
-                    })
-                    .collect();
-                info!("Saved separate frames succesfully: {:?}", filenames);
-                owner.emit_signal("image_save_success", &[Variant::from_string_array(strings)])
+    fn _on_ui_exported_flow_fields(&mut self, owner: &Base, base_filename: String) {
+        match self.save_flow_fields(&base_filename) {
+            Ok(filenames) => Self::emit_saved(owner, "flow fields", &filenames),
+            Err(err) => {
+                let err_str = format!(
+                    "Failed to save flow fields as {}: {}",
+                    base_filename,
+                    err.to_string()
+                );
+                warn!("{}", err_str);
+
+                owner.emit_signal("image_save_failure", &[Variant::from_str(err_str)]);
             }
+        };
+    }
+
+    #[export]
+    fn _on_ui_exported_separate_frames(&mut self, owner: &Base, base_filename: String) {
+        let result = self
+            .save_separate_frames(&base_filename)
+            .and_then(|mut filenames| {
+                filenames.extend(self.save_companion_separate_frames(&base_filename)?);
+                Ok(filenames)
+            });
+
+        match result {
+            Ok(filenames) => Self::emit_saved(owner, "separate frames", &filenames),
             Err(err) => {
                 let err_str = format!(
                     "Failed to save separate frames as {}: {}",
@@ -212,7 +714,7 @@ impl ImageSaver {
                 );
                 warn!("{}", err_str);
 
-                owner.emit_signal("image_save_failure", &[Variant::from_str(err_str)])
+                owner.emit_signal("image_save_failure", &[Variant::from_str(err_str)]);
             }
         };
     }
@@ -222,21 +724,12 @@ impl ImageSaver {
         &mut self,
         owner: &Base,
         filename: String,
-        tex: Ref<ImageTexture, Shared>,
+        spritesheet_params: SpritesheetGenerationParams,
+        fps: f64,
+        loop_animation: bool,
     ) {
-        match self.save_spritesheet(&filename, tex) {
-            Ok(filename) => {
-                let filename_only = Path::new(&filename).file_name().unwrap().to_str().unwrap();
-
-                info!("Saved spritesheet succesfully: {:?}", filename);
-
-                owner.emit_signal(
-                    "image_save_success",
-                    &[Variant::from_string_array(&TypedArray::from_vec(vec![
-                        filename_only.into(),
-                    ]))],
-                )
-            }
+        match self.save_spritesheet(&filename, &spritesheet_params, fps, loop_animation) {
+            Ok(filenames) => Self::emit_saved(owner, "spritesheet", &filenames),
             Err(err) => {
                 let err_str = format!(
                     "Failed to save spritesheet as {}: {}",
@@ -244,7 +737,7 @@ impl ImageSaver {
                     err.to_string()
                 );
                 warn!("{}", err_str);
-                owner.emit_signal("image_save_failure", &[Variant::from_str(err_str)])
+                owner.emit_signal("image_save_failure", &[Variant::from_str(err_str)]);
             }
         };
     }
@@ -4,14 +4,32 @@
 extern crate approx;
 
 mod about_popup;
+mod aseprite;
+mod atlas;
+mod autotune;
+mod correspondence;
 mod datatypes;
+mod error;
+mod flow;
+mod flow_refinement;
+mod flow_visualization;
+mod flowmap;
 mod frame;
+mod frame_size;
 mod global_holder;
+mod global_motion;
+mod godot_resource;
+mod hole_filling;
 mod image_holder;
 mod image_processor;
 mod image_saver;
 mod logging;
+mod quality;
+mod rect_packer;
+mod segmentation;
 mod spritesheet_generator;
+mod stabilization;
+mod temporal;
 mod utility;
 
 use gdnative::prelude::*;
@@ -0,0 +1,286 @@
+use anyhow::{anyhow, Result};
+use gdnative::prelude::*;
+use opencv::core::Mat;
+use serde::Serialize;
+
+use crate::{
+    datatypes::{to_json_variant, EdgeMode, HoleFill, ImgParams},
+    error::check_frame_sizes,
+    flow::FlowField,
+    flow_refinement::refine_flow,
+    frame::{Frame, Frames},
+    hole_filling::{fill_holes, find_holes},
+    image_processor::ImageProcessor,
+    segmentation::{apply_object_flows, calc_object_flows, refine_object_flows},
+    stabilization::{estimate_offsets, shift_frame},
+};
+
+//Leave-one-out evaluation: every other input frame is dropped, interpolated from its neighbours
+//and compared against the real one. Higher PSNR/SSIM and lower alpha error means better flow.
+//The frames go through the same per-pair stages as in the image processor: stabilisation, segmentation,
+//flow clean-up and hole filling. Temporal smoothing and curved motion need the flows of the neighbouring
+//pairs, which skip a frame here, so they aren't measured. Neither are imported flows, correspondence points
+//and region masks, those belong to the real frame pairs.
+
+//The PSNR of identical frames is infinite, so this is reported instead
+const MAX_PSNR: f64 = 100.0;
+//SSIM is computed over square windows of this size, moved by SSIM_STRIDE pixels at a time
+const SSIM_WINDOW: u32 = 8;
+const SSIM_STRIDE: u32 = 4;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FrameQuality {
+    //Index of the held out input frame
+    pub index: usize,
+    pub psnr: f64,
+    pub ssim: f64,
+    pub alpha_error: f64,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct QualityReport {
+    pub frames: Vec<FrameQuality>,
+    pub mean_psnr: f64,
+    pub mean_ssim: f64,
+    pub mean_alpha_error: f64,
+}
+
+impl ToVariant for QualityReport {
+    fn to_variant(&self) -> Variant {
+        to_json_variant(self)
+    }
+}
+
+//Reconstructs every odd frame from the frames around it using img_params, and measures how close it gets.
+//progress is called with a value between 0 and 1 after every frame, returning false cancels the evaluation.
+pub fn evaluate_leave_one_out(
+    frames: &[Frame],
+    img_params: &ImgParams,
+    mut progress: impl FnMut(f64) -> bool,
+) -> Result<Option<QualityReport>> {
+    let n = frames.len();
+    if n < 3 {
+        return Err(anyhow!(
+            "need at least 3 frames to leave one out, found {}",
+            n
+        ));
+    }
+
+    check_frame_sizes(frames)?;
+
+    let edge_mode = if img_params.tileable {
+        EdgeMode::Wrap
+    } else {
+        img_params.edge_mode
+    };
+    let shift_edge_mode = if img_params.tileable {
+        EdgeMode::Wrap
+    } else {
+        EdgeMode::Transparent
+    };
+
+    let offsets = estimate_offsets(frames, img_params.stabilization)?;
+    let stabilized_frames: Frames = frames
+        .iter()
+        .zip(&offsets)
+        .map(|(frame, (x, y))| shift_frame(frame, (-x, -y), shift_edge_mode))
+        .collect();
+    //The held out frame sits halfway between its neighbours
+    let flow_multiplier = 0.5 * img_params.flow_multiplier;
+
+    //(previous, held out, next)
+    let held_out: Vec<(usize, usize, usize)> = (1..n)
+        .step_by(2)
+        .filter_map(|i| {
+            if i + 1 < n {
+                Some((i - 1, i, i + 1))
+            } else if img_params.loop_seamlessly {
+                Some((i - 1, i, 0))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    let mut results = vec![];
+    for (k, &(prev, i, next)) in held_out.iter().enumerate() {
+        let (frame_prev, frame_next) = (&stabilized_frames[prev], &stabilized_frames[next]);
+
+        let reconstructed = if img_params.segment_objects {
+            let mut object_flows = calc_object_flows(
+                frame_prev,
+                frame_next,
+                &img_params.optflow_alg,
+                img_params.global_motion,
+                img_params.tileable,
+                edge_mode,
+            )?;
+            refine_object_flows(&mut object_flows, frame_prev, &img_params.flow_refinement)?;
+            apply_object_flows(
+                frame_prev,
+                frame_next,
+                &object_flows,
+                flow_multiplier,
+                0.5,
+                edge_mode,
+                None,
+            )
+        } else {
+            let mat_prev = Mat::from(frame_prev);
+            let mut flow = ImageProcessor::calc_flow(
+                &mat_prev,
+                &Mat::from(frame_next),
+                &img_params.optflow_alg,
+                img_params.tileable,
+                img_params.global_motion,
+            )?;
+            refine_flow(&mut flow, &mat_prev, &img_params.flow_refinement)?;
+
+            let mut warped =
+                ImageProcessor::apply_flow_to(frame_prev, &flow, flow_multiplier, edge_mode, None);
+            if img_params.hole_fill != HoleFill::None {
+                let field = FlowField::from(&flow);
+                let holes = find_holes(&field, flow_multiplier);
+                fill_holes(
+                    &mut warped,
+                    &holes,
+                    img_params.hole_fill,
+                    frame_next,
+                    &field,
+                    0.5,
+                );
+            }
+            warped
+        };
+
+        //Compare against what the user would see, with or without the jitter put back
+        let result = if img_params.reapply_offsets {
+            let halfway = |a: i32, b: i32| (a as f32 + (b - a) as f32 * 0.5).round() as i32;
+            let offset = (
+                halfway(offsets[prev].0, offsets[next].0),
+                halfway(offsets[prev].1, offsets[next].1),
+            );
+            compare_frames(
+                i,
+                &shift_frame(&reconstructed, offset, shift_edge_mode),
+                &frames[i],
+            )?
+        } else {
+            compare_frames(i, &reconstructed, &stabilized_frames[i])?
+        };
+        results.push(result);
+
+        if !progress((k + 1) as f64 / held_out.len() as f64) {
+            return Ok(None);
+        }
+    }
+
+    let mean =
+        |f: fn(&FrameQuality) -> f64| results.iter().map(f).sum::<f64>() / results.len() as f64;
+
+    Ok(Some(QualityReport {
+        mean_psnr: mean(|q| q.psnr),
+        mean_ssim: mean(|q| q.ssim),
+        mean_alpha_error: mean(|q| q.alpha_error),
+        frames: results,
+    }))
+}
+
+pub fn compare_frames(index: usize, actual: &Frame, expected: &Frame) -> Result<FrameQuality> {
+    if actual.dimensions() != expected.dimensions() {
+        return Err(anyhow!(
+            "frame {} has size {:?}, expected {:?}",
+            index,
+            actual.dimensions(),
+            expected.dimensions()
+        ));
+    }
+
+    Ok(FrameQuality {
+        index,
+        psnr: psnr(actual, expected),
+        ssim: ssim(actual, expected),
+        alpha_error: alpha_error(actual, expected),
+    })
+}
+
+fn psnr(a: &Frame, b: &Frame) -> f64 {
+    let squared_error: f64 = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(x, y)| (*x as f64 - *y as f64).powi(2))
+        .sum();
+    let mse = squared_error / a.as_raw().len().max(1) as f64;
+
+    if mse == 0.0 {
+        MAX_PSNR
+    } else {
+        (10.0 * (255.0 * 255.0 / mse).log10()).min(MAX_PSNR)
+    }
+}
+
+//Luma premultiplied with alpha, so transparent pixels count as black no matter their colour
+fn luma(frame: &Frame, x: u32, y: u32) -> f64 {
+    let [r, g, b, a] = frame[(x, y)].0;
+    (0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64) * a as f64 / 255.0
+}
+
+//Mean structural similarity over all windows, see https://en.wikipedia.org/wiki/Structural_similarity
+fn ssim(a: &Frame, b: &Frame) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (w, h) = a.dimensions();
+    let (win_w, win_h) = (SSIM_WINDOW.min(w), SSIM_WINDOW.min(h));
+    if win_w == 0 || win_h == 0 {
+        return 1.0;
+    }
+
+    let mut total = 0.0;
+    let mut window_count = 0;
+    for top in (0..=h - win_h).step_by(SSIM_STRIDE as usize) {
+        for left in (0..=w - win_w).step_by(SSIM_STRIDE as usize) {
+            let pixels: Vec<(f64, f64)> = (top..top + win_h)
+                .flat_map(|y| (left..left + win_w).map(move |x| (x, y)))
+                .map(|(x, y)| (luma(a, x, y), luma(b, x, y)))
+                .collect();
+            let count = pixels.len() as f64;
+
+            let mean_a = pixels.iter().map(|(la, _)| la).sum::<f64>() / count;
+            let mean_b = pixels.iter().map(|(_, lb)| lb).sum::<f64>() / count;
+            let (mut var_a, mut var_b, mut covar) = (0.0, 0.0, 0.0);
+            for (la, lb) in &pixels {
+                var_a += (la - mean_a).powi(2);
+                var_b += (lb - mean_b).powi(2);
+                covar += (la - mean_a) * (lb - mean_b);
+            }
+            let (var_a, var_b, covar) = (var_a / count, var_b / count, covar / count);
+
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covar + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            window_count += 1;
+        }
+    }
+
+    total / window_count as f64
+}
+
+//Mean error of alpha and alpha-premultiplied colour, between 0 (identical) and 1.
+//Unlike PSNR this ignores the colour of fully transparent pixels.
+fn alpha_error(a: &Frame, b: &Frame) -> f64 {
+    let total: f64 = a
+        .pixels()
+        .zip(b.pixels())
+        .map(|(pa, pb)| {
+            let (alpha_a, alpha_b) = (pa[3] as f64 / 255.0, pb[3] as f64 / 255.0);
+            let color_error = (0..3)
+                .map(|c| (pa[c] as f64 * alpha_a - pb[c] as f64 * alpha_b).abs())
+                .sum::<f64>()
+                / (3.0 * 255.0);
+            (color_error + (alpha_a - alpha_b).abs()) / 2.0
+        })
+        .sum();
+
+    total / (a.width() * a.height()).max(1) as f64
+}
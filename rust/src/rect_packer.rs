@@ -0,0 +1,144 @@
+use std::cmp::Reverse;
+
+//MaxRects bin packer, see "A Thousand Ways to Pack the Bin" by Jukka Jylänki.
+//The bin has a fixed width and unbounded height, and rects are placed using the bottom-left rule
+//so the resulting height stays as small as possible.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FreeRect {
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+}
+
+impl FreeRect {
+    fn contains(&self, other: &FreeRect) -> bool {
+        other.x >= self.x
+            && other.y >= self.y
+            && other.x + other.w <= self.x + self.w
+            && other.y + other.h <= self.y + self.h
+    }
+
+    fn intersects(&self, other: &FreeRect) -> bool {
+        other.x < self.x + self.w
+            && other.x + other.w > self.x
+            && other.y < self.y + self.h
+            && other.y + other.h > self.y
+    }
+
+    //Returns the parts of self that aren't covered by used
+    fn split(&self, used: &FreeRect) -> Vec<FreeRect> {
+        let mut parts = vec![];
+
+        if used.x > self.x {
+            parts.push(FreeRect {
+                w: used.x - self.x,
+                ..*self
+            });
+        }
+        if used.x + used.w < self.x + self.w {
+            parts.push(FreeRect {
+                x: used.x + used.w,
+                w: self.x + self.w - (used.x + used.w),
+                ..*self
+            });
+        }
+        if used.y > self.y {
+            parts.push(FreeRect {
+                h: used.y - self.y,
+                ..*self
+            });
+        }
+        if used.y + used.h < self.y + self.h {
+            parts.push(FreeRect {
+                y: used.y + used.h,
+                h: self.y + self.h - (used.y + used.h),
+                ..*self
+            });
+        }
+
+        parts
+    }
+}
+
+//Packs rects of the given sizes into a bin of the given width, and optionally a maximum height.
+//Returns the position of every rect (in the same order as sizes, None if it didn't fit) and the total height,
+//or None if one of the rects is wider than the bin.
+pub fn pack_rects(
+    sizes: &[(u32, u32)],
+    bin_width: u32,
+    max_height: Option<u32>,
+) -> Option<(Vec<Option<(u32, u32)>>, u32)> {
+    if sizes.iter().any(|(w, _h)| *w > bin_width) {
+        return None;
+    }
+
+    //Stacking everything on top of each other always fits, so that's the height of the bin
+    let stacked_height: u32 = sizes.iter().map(|(_w, h)| h).sum::<u32>().max(1);
+    let bin_height = max_height.map_or(stacked_height, |max| max.min(stacked_height));
+
+    let mut free_rects = vec![FreeRect {
+        x: 0,
+        y: 0,
+        w: bin_width,
+        h: bin_height,
+    }];
+
+    //Placing big rects first gives much tighter results
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|i| Reverse((sizes[*i].1, sizes[*i].0)));
+
+    let mut positions = vec![None; sizes.len()];
+    let mut total_height = 0;
+
+    for i in order {
+        let (w, h) = sizes[i];
+        if w == 0 || h == 0 {
+            positions[i] = Some((0, 0));
+            continue;
+        }
+
+        let best = match free_rects
+            .iter()
+            .filter(|free| free.w >= w && free.h >= h)
+            .min_by_key(|free| (free.y + h, free.x))
+        {
+            Some(best) => *best,
+            None => continue, //Doesn't fit anymore, leave it for the next bin
+        };
+
+        let used = FreeRect {
+            x: best.x,
+            y: best.y,
+            w,
+            h,
+        };
+        positions[i] = Some((used.x, used.y));
+        total_height = total_height.max(used.y + used.h);
+
+        let mut new_free_rects = vec![];
+        for free in free_rects {
+            if free.intersects(&used) {
+                new_free_rects.extend(free.split(&used));
+            } else {
+                new_free_rects.push(free);
+            }
+        }
+
+        //Prune free rects that are fully contained in another one
+        let mut pruned: Vec<FreeRect> = vec![];
+        for (j, a) in new_free_rects.iter().enumerate() {
+            let is_redundant = new_free_rects
+                .iter()
+                .enumerate()
+                .any(|(k, b)| j != k && b.contains(a) && (a != b || k < j));
+            if !is_redundant {
+                pruned.push(*a);
+            }
+        }
+        free_rects = pruned;
+    }
+
+    Some((positions, total_height))
+}
@@ -0,0 +1,464 @@
+use std::{
+    cmp,
+    collections::{HashMap, HashSet},
+};
+
+use image::{Rgba, RgbaImage};
+use opencv::{
+    core::{Mat, Scalar, Vec2f, CV_32FC2},
+    prelude::*,
+};
+
+use crate::{
+    datatypes::{EdgeMode, FlowAlg, FlowRefinement, GlobalMotion},
+    error::BitmapflowResult,
+    flow::FlowField,
+    flow_refinement::refine_flow,
+    frame::Frame,
+    image_processor::{ImageProcessor, RegionMask},
+};
+
+//Splits a frame into its separate sprites (connected opaque areas), so every sprite gets its own flow
+//instead of one dense field that blends the motions of neighbouring sprites together.
+
+//Objects smaller than this (in pixels) just move by the offset of their centroid,
+//running the flow algorithm on every particle would be slow and not much better
+const SMALL_OBJECT_AREA: usize = 64;
+//Empty pixels around an object's crop, so the flow algorithm has some room to work with
+const CROP_MARGIN: u32 = 8;
+//Matches scoring lower than this are considered different objects
+const MIN_MATCH_SCORE: f64 = 0.5;
+
+struct Component {
+    //Bounding box, inclusive
+    min: (u32, u32),
+    max: (u32, u32),
+    pixels: Vec<(u32, u32)>,
+    centroid: (f64, f64),
+    mean_color: [f64; 3],
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Appearance {
+    //An object of frame a that moves to its counterpart in frame b
+    Moving,
+    //An object of frame a without a counterpart, it fades out in place
+    Disappearing,
+    //An object of frame b without a counterpart, it fades in in place
+    Appearing,
+}
+
+//The flow of a single object
+pub struct ObjectFlow {
+    //Area of the frame the flow covers, as (x, y, width, height)
+    rect: (u32, u32, u32, u32),
+    //Which pixels in rect belong to the object, row by row
+    mask: Vec<bool>,
+    flow: Mat,
+    appearance: Appearance,
+}
+
+//Finds the objects in frame_a and frame_b, matches them up and calculates the flow of every matched one.
+//Objects are warped in their own crop, unless the frame tiles or wraps: then wrapping has to go around the whole frame.
+pub fn calc_object_flows(
+    frame_a: &Frame,
+    frame_b: &Frame,
+    optflow_alg: &FlowAlg,
+    global_motion: GlobalMotion,
+    tileable: bool,
+    edge_mode: EdgeMode,
+) -> BitmapflowResult<Vec<ObjectFlow>> {
+    let (labels_a, components_a) = find_components(frame_a, tileable);
+    let (labels_b, components_b) = find_components(frame_b, tileable);
+    let matches = match_components(frame_a, &labels_b, &components_a, &components_b, tileable);
+
+    let (w, h) = frame_a.dimensions();
+    let whole_frame = tileable || edge_mode == EdgeMode::Wrap;
+    let crop_rect = |min: (u32, u32), max: (u32, u32)| {
+        if whole_frame {
+            return (0, 0, w, h);
+        }
+        let (x, y) = (
+            min.0.saturating_sub(CROP_MARGIN),
+            min.1.saturating_sub(CROP_MARGIN),
+        );
+        (
+            x,
+            y,
+            (max.0 + CROP_MARGIN + 1).min(w) - x,
+            (max.1 + CROP_MARGIN + 1).min(h) - y,
+        )
+    };
+    let object_mask = |labels: &[Option<usize>], label: usize, rect: (u32, u32, u32, u32)| {
+        (rect.1..rect.1 + rect.3)
+            .flat_map(|py| (rect.0..rect.0 + rect.2).map(move |px| (px, py)))
+            .map(|(px, py)| labels[(py * w + px) as usize] == Some(label))
+            .collect::<Vec<bool>>()
+    };
+
+    let mut object_flows = vec![];
+    for (index, component) in components_a.iter().enumerate() {
+        let matched = matches.get(&index).map(|j| (*j, &components_b[*j]));
+
+        let rect = match matched {
+            Some((_, other)) => crop_rect(
+                (
+                    component.min.0.min(other.min.0),
+                    component.min.1.min(other.min.1),
+                ),
+                (
+                    component.max.0.max(other.max.0),
+                    component.max.1.max(other.max.1),
+                ),
+            ),
+            None => crop_rect(component.min, component.max),
+        };
+
+        let flow = match matched {
+            Some((label_b, _)) if component.pixels.len() >= SMALL_OBJECT_AREA => {
+                let crop_a = isolate(frame_a, rect, |px, py| {
+                    labels_a[(py * w + px) as usize] == Some(index)
+                });
+                let crop_b = isolate(frame_b, rect, |px, py| {
+                    labels_b[(py * w + px) as usize] == Some(label_b)
+                });
+
+                ImageProcessor::calc_flow(
+                    &Mat::from(&crop_a),
+                    &Mat::from(&crop_b),
+                    optflow_alg,
+                    tileable,
+                    global_motion,
+                )?
+            }
+            Some((_, other)) => {
+                constant_flow(rect, centroid_offset(component, other, (w, h), tileable))?
+            }
+            None => constant_flow(rect, (0.0, 0.0))?,
+        };
+
+        object_flows.push(ObjectFlow {
+            rect,
+            mask: object_mask(&labels_a, index, rect),
+            flow,
+            appearance: if matched.is_some() {
+                Appearance::Moving
+            } else {
+                Appearance::Disappearing
+            },
+        });
+    }
+
+    //Objects that only exist in frame b, otherwise they'd pop in at the keyframe
+    let matched_b: HashSet<usize> = matches.values().copied().collect();
+    for (index, component) in components_b.iter().enumerate() {
+        if matched_b.contains(&index) {
+            continue;
+        }
+
+        let rect = crop_rect(component.min, component.max);
+        object_flows.push(ObjectFlow {
+            rect,
+            mask: object_mask(&labels_b, index, rect),
+            flow: constant_flow(rect, (0.0, 0.0))?,
+            appearance: Appearance::Appearing,
+        });
+    }
+
+    Ok(object_flows)
+}
+
+//Cleans up the flow of every moving object, with the object itself as the guide
+pub fn refine_object_flows(
+    object_flows: &mut [ObjectFlow],
+    frame_a: &Frame,
+    refinement: &FlowRefinement,
+) -> BitmapflowResult<()> {
+    for object in object_flows
+        .iter_mut()
+        .filter(|object| object.appearance == Appearance::Moving)
+    {
+        let (x, y, w, _h) = object.rect;
+        let mask = &object.mask;
+        let crop = isolate(frame_a, object.rect, |px, py| {
+            mask[((py - y) * w + (px - x)) as usize]
+        });
+        refine_flow(&mut object.flow, &Mat::from(&crop), refinement)?;
+    }
+
+    Ok(())
+}
+
+//Warps every object separately and composites them. frame_b is the frame the appearing objects come from.
+//t is how far along the frame pair we are, unmatched objects are faded out or in by it.
+//The region mask is applied to the composited frame, as it covers the whole frame rather than one object.
+pub fn apply_object_flows(
+    frame: &Frame,
+    frame_b: &Frame,
+    object_flows: &[ObjectFlow],
+    flow_multiplier: f32,
+    t: f32,
+    edge_mode: EdgeMode,
+    region_mask: Option<&RegionMask>,
+) -> Frame {
+    let mut output = RgbaImage::new(frame.width(), frame.height());
+
+    for object in object_flows {
+        let (x, y, w, _h) = object.rect;
+        let source = match object.appearance {
+            Appearance::Appearing => frame_b,
+            Appearance::Moving | Appearance::Disappearing => frame,
+        };
+        let crop = isolate(source, object.rect, |px, py| {
+            object.mask[((py - y) * w + (px - x)) as usize]
+        });
+
+        let (warped, opacity) = match object.appearance {
+            Appearance::Moving => (
+                ImageProcessor::apply_flow_to(
+                    &crop,
+                    &object.flow,
+                    flow_multiplier,
+                    edge_mode,
+                    None,
+                ),
+                1.0,
+            ),
+            Appearance::Disappearing => (crop, 1.0 - t),
+            Appearance::Appearing => (crop, t),
+        };
+
+        for (px, py, pixel) in warped.enumerate_pixels() {
+            blend_over(output.get_pixel_mut(x + px, y + py), *pixel, opacity);
+        }
+    }
+
+    if let Some(region_mask) = region_mask {
+        for (x, y, pixel) in output.enumerate_pixels_mut() {
+            *pixel = region_mask.blend(x, y, *pixel, frame[(x, y)]);
+        }
+    }
+
+    Frame(output)
+}
+
+//Combines the object flows into one field for the whole frame, for exporting and visualizing.
+//Pixels that aren't part of any object of frame a don't move.
+pub fn object_flows_to_field(object_flows: &[ObjectFlow], width: u32, height: u32) -> FlowField {
+    let mut field = FlowField {
+        width: width as usize,
+        height: height as usize,
+        data: vec![[0.0, 0.0]; (width * height) as usize],
+    };
+
+    for object in object_flows {
+        if object.appearance == Appearance::Appearing {
+            continue;
+        }
+
+        let (x, y, w, h) = object.rect;
+        for py in 0..h {
+            for px in 0..w {
+                if object.mask[(py * w + px) as usize] {
+                    let flow: Vec2f = *object.flow.at_2d(py as i32, px as i32).unwrap();
+                    field.data[((y + py) * width + x + px) as usize] = [flow[0], flow[1]];
+                }
+            }
+        }
+    }
+
+    field
+}
+
+//Labels every 8-connected area of non-transparent pixels. Returns the label of every pixel and the areas.
+//With wrap, areas continue across the edges of the frame.
+fn find_components(frame: &Frame, wrap: bool) -> (Vec<Option<usize>>, Vec<Component>) {
+    let (w, h) = frame.dimensions();
+    let (iw, ih) = (w as i64, h as i64);
+    let mut labels = vec![None; (w * h) as usize];
+    let mut components = vec![];
+
+    for start_y in 0..h {
+        for start_x in 0..w {
+            if frame[(start_x, start_y)][3] == 0
+                || labels[(start_y * w + start_x) as usize].is_some()
+            {
+                continue;
+            }
+
+            let label = components.len();
+            labels[(start_y * w + start_x) as usize] = Some(label);
+            //Unwrapped positions are kept too, so an area crossing the edge has a sensible centroid
+            let mut stack = vec![(start_x, start_y, start_x as i64, start_y as i64)];
+            let mut pixels = vec![];
+            let mut unwrapped_sum = (0.0, 0.0);
+
+            while let Some((x, y, ux, uy)) = stack.pop() {
+                pixels.push((x, y));
+                unwrapped_sum.0 += ux as f64;
+                unwrapped_sum.1 += uy as f64;
+
+                for dy in -1..=1 {
+                    for dx in -1..=1 {
+                        let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                        let (nx, ny) = if wrap {
+                            (nx.rem_euclid(iw), ny.rem_euclid(ih))
+                        } else if nx < 0 || ny < 0 || nx >= iw || ny >= ih {
+                            continue;
+                        } else {
+                            (nx, ny)
+                        };
+
+                        let index = (ny * iw + nx) as usize;
+                        if frame[(nx as u32, ny as u32)][3] != 0 && labels[index].is_none() {
+                            labels[index] = Some(label);
+                            stack.push((nx as u32, ny as u32, ux + dx, uy + dy));
+                        }
+                    }
+                }
+            }
+
+            components.push(Component::new(frame, pixels, unwrapped_sum));
+        }
+    }
+
+    (labels, components)
+}
+
+impl Component {
+    fn new(frame: &Frame, pixels: Vec<(u32, u32)>, unwrapped_sum: (f64, f64)) -> Self {
+        let count = pixels.len() as f64;
+        let (w, h) = frame.dimensions();
+        let (mut min, mut max) = ((u32::MAX, u32::MAX), (0, 0));
+        let mut sum_color = [0.0; 3];
+
+        for &(x, y) in &pixels {
+            min = (min.0.min(x), min.1.min(y));
+            max = (max.0.max(x), max.1.max(y));
+            for (c, sum) in sum_color.iter_mut().enumerate() {
+                *sum += frame[(x, y)][c] as f64;
+            }
+        }
+
+        Component {
+            min,
+            max,
+            centroid: (
+                (unwrapped_sum.0 / count).rem_euclid(w as f64),
+                (unwrapped_sum.1 / count).rem_euclid(h as f64),
+            ),
+            mean_color: [
+                sum_color[0] / count,
+                sum_color[1] / count,
+                sum_color[2] / count,
+            ],
+            pixels,
+        }
+    }
+}
+
+//How far b's centroid is from a's. When wrapping, the shortest way around is taken.
+fn centroid_offset(a: &Component, b: &Component, size: (u32, u32), wrap: bool) -> (f64, f64) {
+    let (mut dx, mut dy) = (b.centroid.0 - a.centroid.0, b.centroid.1 - a.centroid.1);
+    if wrap {
+        let (w, h) = (size.0 as f64, size.1 as f64);
+        dx -= w * (dx / w).round();
+        dy -= h * (dy / h).round();
+    }
+    (dx, dy)
+}
+
+//Greedily pairs up the components of both frames, best score first.
+//The score rewards overlap and similar colour and size, and punishes distance.
+fn match_components(
+    frame_a: &Frame,
+    labels_b: &[Option<usize>],
+    components_a: &[Component],
+    components_b: &[Component],
+    wrap: bool,
+) -> HashMap<usize, usize> {
+    let (w, h) = frame_a.dimensions();
+    let diagonal = (w as f64).hypot(h as f64);
+
+    let mut scores = vec![];
+    for (i, a) in components_a.iter().enumerate() {
+        let mut overlaps: HashMap<usize, usize> = HashMap::new();
+        for (x, y) in &a.pixels {
+            if let Some(j) = labels_b[(y * w + x) as usize] {
+                *overlaps.entry(j).or_default() += 1;
+            }
+        }
+
+        for (j, b) in components_b.iter().enumerate() {
+            let (area_a, area_b) = (a.pixels.len() as f64, b.pixels.len() as f64);
+            let overlap = *overlaps.get(&j).unwrap_or(&0) as f64;
+            let iou = overlap / (area_a + area_b - overlap);
+
+            let color_distance = (0..3)
+                .map(|c| (a.mean_color[c] - b.mean_color[c]).powi(2))
+                .sum::<f64>()
+                .sqrt()
+                / (255.0 * 3f64.sqrt());
+            let appearance = (1.0 - color_distance) * area_a.min(area_b) / area_a.max(area_b);
+
+            let (dx, dy) = centroid_offset(a, b, (w, h), wrap);
+            let distance = dx.hypot(dy) / diagonal;
+
+            let score = iou + appearance - distance;
+            if score >= MIN_MATCH_SCORE {
+                scores.push((score, i, j));
+            }
+        }
+    }
+
+    scores.sort_by(|(a, ..), (b, ..)| b.partial_cmp(a).unwrap_or(cmp::Ordering::Equal));
+
+    let mut matches = HashMap::new();
+    let mut taken_b = vec![false; components_b.len()];
+    for (_score, i, j) in scores {
+        if !matches.contains_key(&i) && !taken_b[j] {
+            matches.insert(i, j);
+            taken_b[j] = true;
+        }
+    }
+
+    matches
+}
+
+//Copies rect out of frame, keeping only the pixels for which include returns true (in frame coordinates)
+fn isolate(frame: &Frame, rect: (u32, u32, u32, u32), include: impl Fn(u32, u32) -> bool) -> Frame {
+    let (x, y, w, h) = rect;
+    Frame(RgbaImage::from_fn(w, h, |px, py| {
+        if include(x + px, y + py) {
+            frame[(x + px, y + py)]
+        } else {
+            Rgba([0, 0, 0, 0])
+        }
+    }))
+}
+
+fn constant_flow(rect: (u32, u32, u32, u32), (dx, dy): (f64, f64)) -> BitmapflowResult<Mat> {
+    Ok(Mat::new_rows_cols_with_default(
+        rect.3 as i32,
+        rect.2 as i32,
+        CV_32FC2,
+        Scalar::new(dx, dy, 0.0, 0.0),
+    )?)
+}
+
+//Alpha compositing of src over dst, with src's alpha multiplied by opacity
+fn blend_over(dst: &mut Rgba<u8>, src: Rgba<u8>, opacity: f32) {
+    let src_alpha = src[3] as f32 / 255.0 * opacity;
+    if src_alpha <= 0.0 {
+        return;
+    }
+    let dst_alpha = dst[3] as f32 / 255.0;
+    let out_alpha = src_alpha + dst_alpha * (1.0 - src_alpha);
+
+    for c in 0..3 {
+        dst[c] = ((src[c] as f32 * src_alpha + dst[c] as f32 * dst_alpha * (1.0 - src_alpha))
+            / out_alpha)
+            .round() as u8;
+    }
+    dst[3] = (out_alpha * 255.0).round() as u8;
+}
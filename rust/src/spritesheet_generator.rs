@@ -1,28 +1,75 @@
-use std::time::Instant;
+use std::{collections::HashMap, iter, time::Instant};
 
 use gdnative::{api::ImageTexture, prelude::*};
-use image::{imageops::replace, ImageBuffer, RgbaImage};
+use image::{imageops::replace, GenericImageView, ImageBuffer, Pixel, RgbaImage};
 use log::*;
 
-use crate::{datatypes::SpritesheetGenerationParams, utility::do_with_image_holder};
+use crate::{
+    atlas::{AtlasRect, AtlasSize},
+    datatypes::{SpritesheetGenerationParams, SpritesheetPacking},
+    error::{check_frame_sizes, BitmapflowError, BitmapflowResult},
+    frame::Frame,
+    rect_packer::pack_rects,
+    utility::{do_with_image_holder, do_with_image_processor},
+};
+
+pub struct SpritesheetLayout {
+    //Size of every page
+    pub pages: Vec<(usize, usize)>,
+    pub frames: Vec<SpritesheetLayoutFrame>,
+}
+
+pub struct SpritesheetLayoutFrame {
+    //Index of the page the frame is stored on
+    pub page: usize,
+    //Where the frame is stored in the spritesheet
+    pub rect: AtlasRect,
+    //Which part of the original frame is stored (equal to the full frame if it isn't trimmed)
+    pub sprite_source_size: AtlasRect,
+    //Size of the original frame
+    pub source_size: AtlasSize,
+}
 
 type Base = Node;
 //Base refers to the type SpritesheetGenerator inherits from. In this case it's Node (because #[inherit(Node)])
 
 #[derive(NativeClass)]
 #[inherit(Base)]
+#[register_with(Self::register_signals)]
 pub struct SpritesheetGenerator {}
 
 impl SpritesheetGenerator {
     fn new(_owner: &Base) -> Self {
         SpritesheetGenerator {}
     }
+
+    fn register_signals(builder: &ClassBuilder<Self>) {
+        builder.add_signal(Signal {
+            name: "error_occured",
+            args: &[SignalArgument {
+                name: "error",
+                default: Variant::from_str("Dummy error"),
+                export_info: ExportInfo::new(VariantType::GodotString),
+                usage: PropertyUsage::DEFAULT,
+            }],
+        });
+    }
+
+    fn emit_error(owner: &Base, err: BitmapflowError) {
+        let err_str = format!("Failed to generate spritesheet: {}", err);
+        warn!("{}", err_str);
+        owner.emit_signal("error_occured", &[Variant::from_str(err_str)]);
+    }
 }
 
 #[methods]
 impl SpritesheetGenerator {
     #[export]
-    fn get_optimal_spritesheet_params(&mut self, _owner: &Base) -> SpritesheetGenerationParams {
+    fn get_optimal_spritesheet_params(
+        &mut self,
+        owner: &Base,
+        spritesheet_params: SpritesheetGenerationParams,
+    ) -> SpritesheetGenerationParams {
         // Maximize spritesheet squareness and try to get close to a power of 2
 
         // This is not the fastest algorithm,
@@ -35,13 +82,26 @@ impl SpritesheetGenerator {
         })
         .unwrap();
 
+        let frame_size = match Self::get_frame_size() {
+            Ok(frame_size) => frame_size,
+            Err(err) => {
+                Self::emit_error(owner, err);
+                return spritesheet_params;
+            }
+        };
+
         let start = Instant::now();
 
         let closest_to_po2 = (1..)
             .take_while(|frames_per_row| *frames_per_row <= frame_count)
-            .map(|frames_per_row| SpritesheetGenerationParams { frames_per_row })
+            .map(|frames_per_row| SpritesheetGenerationParams {
+                frames_per_row,
+                ..spritesheet_params.clone()
+            })
             .min_by_key(|params| {
-                let (w, h) = Self::get_spritesheet_size(params);
+                //Fewer pages always wins, after that look at the size of the first page
+                let layout = Self::get_grid_layout_for(params, frame_count, frame_size);
+                let (w, h) = layout.pages[0];
                 let (w, h) = (w as u64, h as u64);
 
                 let (w_nearest_po2, h_nearest_po2) = (w.next_power_of_two(), h.next_power_of_two());
@@ -62,7 +122,7 @@ impl SpritesheetGenerator {
                     cost
                 );*/
 
-                cost
+                (layout.pages.len(), cost)
             })
             .unwrap();
         let duration = start.elapsed();
@@ -76,85 +136,466 @@ impl SpritesheetGenerator {
         closest_to_po2
     }
 
-    fn get_frame_size() -> (usize, usize) {
+    fn get_frame_size() -> BitmapflowResult<(usize, usize)> {
         do_with_image_holder(|imageholder, _owner| {
-            let frames = &imageholder.output_frames;
+            let (frame_width, frame_height) = check_frame_sizes(&imageholder.output_frames)?;
 
-            assert!(frames
-                .iter()
-                .all(|f| (f.width(), f.height()) == (frames[0].width(), frames[0].height())));
+            Ok((frame_width as usize, frame_height as usize))
+        })
+        .unwrap()
+    }
+
+    //Pages are rounded up to a power of two at the end, so the max has to be one too or they'd grow past it
+    fn get_max_texture_size(spritesheet_params: &SpritesheetGenerationParams) -> Option<u32> {
+        match spritesheet_params.max_texture_size {
+            0 => None,
+            max if spritesheet_params.power_of_two => {
+                let max = max as u32;
+                Some(1 << (31 - max.leading_zeros()))
+            }
+            max => Some(max as u32),
+        }
+    }
+
+    fn finalize_page_size(
+        spritesheet_params: &SpritesheetGenerationParams,
+        (w, h): (u32, u32),
+    ) -> (usize, usize) {
+        if spritesheet_params.power_of_two {
+            (
+                w.next_power_of_two() as usize,
+                h.next_power_of_two() as usize,
+            )
+        } else {
+            (w as usize, h as usize)
+        }
+    }
 
-            let frame_width = frames[0].width() as usize;
-            let frame_height = frames[0].height() as usize;
+    //The location of every output frame in the spritesheet, in the same order as the frames
+    pub fn get_layout(
+        spritesheet_params: &SpritesheetGenerationParams,
+    ) -> BitmapflowResult<SpritesheetLayout> {
+        match spritesheet_params.packing {
+            SpritesheetPacking::Grid => Self::get_grid_layout(spritesheet_params),
+            SpritesheetPacking::Packed => Self::get_packed_layout(spritesheet_params),
+        }
+    }
 
-            (frame_width, frame_height)
+    fn get_grid_layout(
+        spritesheet_params: &SpritesheetGenerationParams,
+    ) -> BitmapflowResult<SpritesheetLayout> {
+        let frame_count = do_with_image_holder(|imageholder, _owner| {
+            let frames = &imageholder.output_frames;
+            frames.len()
         })
-        .unwrap()
+        .unwrap();
+
+        Ok(Self::get_grid_layout_for(
+            spritesheet_params,
+            frame_count,
+            Self::get_frame_size()?,
+        ))
     }
 
-    fn get_spritesheet_size(spritesheet_params: &SpritesheetGenerationParams) -> (usize, usize) {
+    //Lays out frame_count frames of the given size on a grid, regardless of what's in the ImageHolder
+    pub fn get_grid_layout_for(
+        spritesheet_params: &SpritesheetGenerationParams,
+        frame_count: usize,
+        (frame_width, frame_height): (usize, usize),
+    ) -> SpritesheetLayout {
+        if frame_count == 0 {
+            return SpritesheetLayout {
+                pages: vec![],
+                frames: vec![],
+            };
+        }
+
+        let (w, h) = (frame_width as u32, frame_height as u32);
+
+        let margin = spritesheet_params.margin as u32;
+        let extrude = spritesheet_params.extrude as u32;
+        let spacing = spritesheet_params.spacing as u32;
+
+        let (cell_w, cell_h) = (w + 2 * extrude, h + 2 * extrude);
+        let (step_x, step_y) = (cell_w + spacing, cell_h + spacing);
+
+        //How many cells fit next to each other on a single page
+        let max_size = Self::get_max_texture_size(spritesheet_params);
+        let cells_that_fit = |cell: u32, step: u32| match max_size {
+            Some(max) if max >= cell + 2 * margin => {
+                ((max - cell - 2 * margin) / step + 1) as usize
+            }
+            Some(max) => {
+                warn!(
+                    "Frames of {}px don't fit in the max texture size of {}px",
+                    cell, max
+                );
+                1
+            }
+            None => usize::MAX,
+        };
+
+        let frames_per_row = spritesheet_params
+            .frames_per_row
+            .min(frame_count)
+            .min(cells_that_fit(cell_w, step_x))
+            .max(1);
+        let rows_per_page = cells_that_fit(cell_h, step_y);
+        let frames_per_page = frames_per_row.saturating_mul(rows_per_page);
+
+        let layout_frames = (0..frame_count)
+            .map(|i| {
+                let index_on_page = i % frames_per_page;
+                SpritesheetLayoutFrame {
+                    page: i / frames_per_page,
+                    rect: AtlasRect {
+                        x: margin + extrude + (index_on_page % frames_per_row) as u32 * step_x,
+                        y: margin + extrude + (index_on_page / frames_per_row) as u32 * step_y,
+                        w,
+                        h,
+                    },
+                    sprite_source_size: AtlasRect { x: 0, y: 0, w, h },
+                    source_size: AtlasSize { w, h },
+                }
+            })
+            .collect();
+
+        let page_count = frame_count.saturating_sub(1) / frames_per_page + 1;
+        let pages = (0..page_count)
+            .map(|page| {
+                let frames_on_page = (frame_count - page * frames_per_page).min(frames_per_page);
+                let columns = frames_on_page.min(frames_per_row) as u32;
+                let rows = ((frames_on_page + frames_per_row - 1) / frames_per_row) as u32;
+
+                Self::finalize_page_size(
+                    spritesheet_params,
+                    (
+                        cell_w * columns + spacing * columns.saturating_sub(1) + 2 * margin,
+                        cell_h * rows + spacing * rows.saturating_sub(1) + 2 * margin,
+                    ),
+                )
+            })
+            .collect();
+
+        SpritesheetLayout {
+            pages,
+            frames: layout_frames,
+        }
+    }
+
+    //Returns the bounding box of all pixels that aren't fully transparent
+    fn get_alpha_bounds(frame: &Frame) -> AtlasRect {
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+
+        for (x, y, pixel) in frame.enumerate_pixels() {
+            if pixel[3] > 0 {
+                min_x = min_x.min(x);
+                min_y = min_y.min(y);
+                max_x = max_x.max(x);
+                max_y = max_y.max(y);
+            }
+        }
+
+        if min_x > max_x {
+            //Fully transparent, keep a single pixel so every frame still has a rect
+            return AtlasRect {
+                x: 0,
+                y: 0,
+                w: 1,
+                h: 1,
+            };
+        }
+
+        AtlasRect {
+            x: min_x,
+            y: min_y,
+            w: max_x - min_x + 1,
+            h: max_y - min_y + 1,
+        }
+    }
+
+    //Repeats the border pixels of the rect outwards, so filtering samples the frame's own edge instead of its neighbour
+    fn extrude_edges<P: Pixel + 'static>(
+        spritesheet: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+        rect: &AtlasRect,
+        extrude: u32,
+    ) {
+        if extrude == 0 {
+            return;
+        }
+
+        let (x0, y0) = (rect.x - extrude, rect.y - extrude);
+        let (x1, y1) = (rect.x + rect.w + extrude, rect.y + rect.h + extrude);
+
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let inside_x = x.clamp(rect.x, rect.x + rect.w - 1);
+                let inside_y = y.clamp(rect.y, rect.y + rect.h - 1);
+                if (inside_x, inside_y) != (x, y) {
+                    let pixel = *spritesheet.get_pixel(inside_x, inside_y);
+                    spritesheet.put_pixel(x, y, pixel);
+                }
+            }
+        }
+    }
+
+    fn get_packed_layout(
+        spritesheet_params: &SpritesheetGenerationParams,
+    ) -> BitmapflowResult<SpritesheetLayout> {
+        let companions = do_with_image_processor(|imageprocessor, _owner| {
+            imageprocessor.get_output_companion_frames()
+        })
+        .unwrap();
+
         do_with_image_holder(|imageholder, _owner| {
             let frames = &imageholder.output_frames;
-            let frames_per_row = spritesheet_params.frames_per_row.min(frames.len());
+            if frames.is_empty() {
+                return Err(BitmapflowError::NoFrames);
+            }
+
+            let start = Instant::now();
+
+            let trims: Vec<AtlasRect> = frames.iter().map(Self::get_alpha_bounds).collect();
+
+            //Identical trimmed frames are only stored once (only if their companion frames are identical too)
+            let mut unique_indices: HashMap<Vec<u8>, usize> = HashMap::new();
+            let mut unique_sizes = vec![];
+            let frame_to_unique: Vec<usize> = frames
+                .iter()
+                .zip(&trims)
+                .enumerate()
+                .map(|(i, (frame, trim))| {
+                    let mut key: Vec<u8> = trim.w.to_le_bytes().to_vec();
+                    key.extend(&trim.h.to_le_bytes());
+                    let companion_frames = companions.iter().filter_map(|(_, f)| f.get(i));
+                    for frame in iter::once(frame).chain(companion_frames) {
+                        for (_x, _y, pixel) in frame.view(trim.x, trim.y, trim.w, trim.h).pixels() {
+                            key.extend(&pixel.0);
+                        }
+                    }
+
+                    let next_index = unique_sizes.len();
+                    *unique_indices.entry(key).or_insert_with(|| {
+                        unique_sizes.push((trim.w, trim.h));
+                        next_index
+                    })
+                })
+                .collect();
 
-            let (frame_width, frame_height) = Self::get_frame_size();
-            let rows = (frames.len() as f32 / frames_per_row as f32).ceil() as usize;
+            //Every packed rect reserves room for its extrusion and the spacing after it
+            let margin = spritesheet_params.margin as u32;
+            let extrude = spritesheet_params.extrude as u32;
+            let padding = 2 * extrude + spritesheet_params.spacing as u32;
+            let padded_sizes: Vec<(u32, u32)> = unique_sizes
+                .iter()
+                .map(|(w, h)| (w + padding, h + padding))
+                .collect();
 
-            let final_img_width = frame_width * frames_per_row;
-            let final_img_height = frame_height * rows;
+            let max_size = Self::get_max_texture_size(spritesheet_params);
+            let spacing = spritesheet_params.spacing as u32;
+            //Room for the padded rects on a single page. The trailing spacing of the last rect may overlap the margin.
+            let max_inner_size = max_size.map(|max| (max + spacing).saturating_sub(2 * margin));
+
+            //Keep filling pages until every unique frame has a place
+            let mut unique_positions = vec![(0, 0, 0); unique_sizes.len()];
+            let mut pages = vec![];
+            let mut remaining: Vec<usize> = (0..unique_sizes.len()).collect();
+
+            while !remaining.is_empty() {
+                let sizes: Vec<(u32, u32)> = remaining.iter().map(|i| padded_sizes[*i]).collect();
+
+                //Try a bunch of bin widths and keep the one that fits the most frames, and then the smallest (and squarest) result
+                let widest = sizes.iter().map(|(w, _h)| *w).max().unwrap_or(1);
+                let total_width: u32 = sizes.iter().map(|(w, _h)| *w).sum();
+                let max_width = max_inner_size.map_or(total_width, |max| max.min(total_width));
+                let step = (max_width.saturating_sub(widest) / 64).max(1);
+
+                let best = (widest..=max_width)
+                    .step_by(step as usize)
+                    .filter_map(|bin_width| pack_rects(&sizes, bin_width, max_inner_size))
+                    .map(|(positions, height)| {
+                        let width = sizes
+                            .iter()
+                            .zip(&positions)
+                            .filter_map(|((w, _h), pos)| pos.map(|(x, _y)| x + w))
+                            .max()
+                            .unwrap_or(0);
+                        (positions, width, height)
+                    })
+                    .filter(|(_positions, width, _height)| *width > 0)
+                    .min_by_key(|(positions, w, h)| {
+                        let unplaced = positions.iter().filter(|pos| pos.is_none()).count();
+                        (unplaced, *w as u64 * *h as u64, (*w).max(*h))
+                    });
+
+                //If not even a single frame fits, it gets a page of its own that's bigger than the max
+                let (positions, width, height) = best.unwrap_or_else(|| {
+                    let (w, h) = sizes[0];
+                    warn!(
+                        "A frame of {}x{}px doesn't fit in the max texture size of {:?}px",
+                        w, h, max_size
+                    );
+                    let mut positions = vec![None; sizes.len()];
+                    positions[0] = Some((0, 0));
+                    (positions, w, h)
+                });
+
+                let page = pages.len();
+                let mut still_remaining = vec![];
+                for (unique, pos) in remaining.iter().zip(&positions) {
+                    match pos {
+                        Some((x, y)) => unique_positions[*unique] = (page, *x, *y),
+                        None => still_remaining.push(*unique),
+                    }
+                }
+                remaining = still_remaining;
+
+                //The spacing after the last rect isn't needed, but the margin around everything is
+                pages.push(Self::finalize_page_size(
+                    spritesheet_params,
+                    (width - spacing + 2 * margin, height - spacing + 2 * margin),
+                ));
+            }
 
-            (final_img_width, final_img_height)
+            let layout_frames = frames
+                .iter()
+                .zip(&trims)
+                .zip(&frame_to_unique)
+                .map(|((frame, trim), unique)| {
+                    let (page, x, y) = unique_positions[*unique];
+                    SpritesheetLayoutFrame {
+                        page,
+                        rect: AtlasRect {
+                            x: x + margin + extrude,
+                            y: y + margin + extrude,
+                            w: trim.w,
+                            h: trim.h,
+                        },
+                        sprite_source_size: *trim,
+                        source_size: AtlasSize {
+                            w: frame.width(),
+                            h: frame.height(),
+                        },
+                    }
+                })
+                .collect();
+
+            info!(
+                "Packed {} frames ({} unique) into pages {:?} in {:?}",
+                frames.len(),
+                unique_sizes.len(),
+                pages,
+                start.elapsed()
+            );
+
+            Ok(SpritesheetLayout {
+                pages,
+                frames: layout_frames,
+            })
         })
         .unwrap()
     }
 
-    #[export]
-    fn generate_spritesheet(
-        &mut self,
-        _owner: &Base,
-        spritesheet_params: SpritesheetGenerationParams,
-    ) -> Ref<ImageTexture, Unique> {
+    pub fn render_page(
+        spritesheet_params: &SpritesheetGenerationParams,
+        layout: &SpritesheetLayout,
+        page: usize,
+    ) -> RgbaImage {
         do_with_image_holder(|imageholder, _owner| {
-            let frames = &imageholder.output_frames;
-            let frames_per_row = spritesheet_params.frames_per_row.min(frames.len());
+            let images: Vec<&RgbaImage> = imageholder.output_frames.iter().map(|f| &f.0).collect();
+            Self::render_images_page(&images, spritesheet_params, layout, page)
+        })
+        .unwrap()
+    }
 
-            let (frame_width, frame_height) = Self::get_frame_size();
-            let (final_img_width, final_img_height) =
-                Self::get_spritesheet_size(&spritesheet_params);
+    //Renders a single page of any kind of images (e.g. 16-bit flow maps) using the given layout
+    pub fn render_images_page<P: Pixel + 'static>(
+        images: &[&ImageBuffer<P, Vec<P::Subpixel>>],
+        spritesheet_params: &SpritesheetGenerationParams,
+        layout: &SpritesheetLayout,
+        page: usize,
+    ) -> ImageBuffer<P, Vec<P::Subpixel>> {
+        let (final_img_width, final_img_height) = layout.pages[page];
 
-            let mut spritesheet: RgbaImage =
-                ImageBuffer::new(final_img_width as u32, final_img_height as u32);
+        let mut spritesheet = ImageBuffer::new(final_img_width as u32, final_img_height as u32);
 
-            let start = Instant::now();
-            for (i, frame) in frames.iter().enumerate() {
-                let x = (i % frames_per_row) * frame_width;
-                let y = (i / frames_per_row) * frame_height;
-                replace(&mut spritesheet, &(frame.0), x as u32, y as u32);
+        let start = Instant::now();
+        for (image, layout_frame) in images.iter().zip(&layout.frames) {
+            if layout_frame.page != page {
+                continue;
             }
-            let duration = start.elapsed();
 
-            info!("Time elapsed in replacement is: {:?}", duration);
+            let AtlasRect { x, y, w, h } = layout_frame.sprite_source_size;
+            let trimmed = image.view(x, y, w, h);
+            replace(
+                &mut spritesheet,
+                &trimmed,
+                layout_frame.rect.x,
+                layout_frame.rect.y,
+            );
+            Self::extrude_edges(
+                &mut spritesheet,
+                &layout_frame.rect,
+                spritesheet_params.extrude as u32,
+            );
+        }
+        let duration = start.elapsed();
 
-            let data: Vec<u8> = spritesheet
-                .pixels()
-                .flat_map(|rgba| rgba.0.iter().cloned())
-                .collect();
+        info!("Time elapsed in replacement is: {:?}", duration);
 
-            let texture = ImageTexture::new();
-            let image = Image::new();
+        spritesheet
+    }
 
-            image.create_from_data(
-                final_img_width as i64,
-                final_img_height as i64,
-                false,
-                Image::FORMAT_RGBA8,
-                TypedArray::from_vec(data),
-            );
+    #[export]
+    fn get_page_count(
+        &mut self,
+        owner: &Base,
+        spritesheet_params: SpritesheetGenerationParams,
+    ) -> usize {
+        match Self::get_layout(&spritesheet_params) {
+            Ok(layout) => layout.pages.len(),
+            Err(err) => {
+                Self::emit_error(owner, err);
+                0
+            }
+        }
+    }
 
-            texture.create_from_image(image, 0);
+    //Generates the first page, the rest is only generated when saving
+    #[export]
+    fn generate_spritesheet(
+        &mut self,
+        owner: &Base,
+        spritesheet_params: SpritesheetGenerationParams,
+    ) -> Option<Ref<ImageTexture, Unique>> {
+        let layout = match Self::get_layout(&spritesheet_params) {
+            Ok(layout) => layout,
+            Err(err) => {
+                Self::emit_error(owner, err);
+                return None;
+            }
+        };
+        let spritesheet = Self::render_page(&spritesheet_params, &layout, 0);
+        let (final_img_width, final_img_height) = spritesheet.dimensions();
+
+        let data: Vec<u8> = spritesheet
+            .pixels()
+            .flat_map(|rgba| rgba.0.iter().cloned())
+            .collect();
+
+        let texture = ImageTexture::new();
+        let image = Image::new();
+
+        image.create_from_data(
+            final_img_width as i64,
+            final_img_height as i64,
+            false,
+            Image::FORMAT_RGBA8,
+            TypedArray::from_vec(data),
+        );
 
-            texture
-        })
-        .unwrap()
+        texture.create_from_image(image, 0);
+
+        Some(texture)
     }
 }
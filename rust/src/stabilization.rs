@@ -0,0 +1,184 @@
+use image::{Rgba, RgbaImage};
+use opencv::{
+    core::{Mat, CV_32FC1},
+    imgproc,
+    prelude::*,
+};
+
+use crate::{
+    datatypes::{EdgeMode, Stabilization},
+    error::BitmapflowResult,
+    flow::FlowField,
+    frame::Frame,
+};
+
+//Removes the global translation between frames (e.g. a character drifting because of
+//inconsistent canvas placement), so it doesn't get interpolated as motion.
+
+//Alpha below this doesn't count as part of the sprite when looking for the feet
+const BASELINE_ALPHA_THRESHOLD: u8 = 128;
+
+//Returns how far the contents of every frame moved relative to the first frame, in whole pixels
+pub fn estimate_offsets(
+    frames: &[Frame],
+    stabilization: Stabilization,
+) -> BitmapflowResult<Vec<(i32, i32)>> {
+    let first = match frames.first() {
+        Some(first) => first,
+        None => return Ok(vec![]),
+    };
+
+    let offsets = match stabilization {
+        Stabilization::None => vec![(0.0, 0.0); frames.len()],
+        Stabilization::AlphaCentroid | Stabilization::FeetBaseline => {
+            let anchor = |frame: &Frame| {
+                let (x, y) = alpha_centroid(frame)?;
+                if stabilization == Stabilization::FeetBaseline {
+                    Some((x, baseline(frame).unwrap_or(y)))
+                } else {
+                    Some((x, y))
+                }
+            };
+
+            let first_anchor = anchor(first);
+            frames
+                .iter()
+                .map(|frame| match (anchor(frame), first_anchor) {
+                    (Some((x, y)), Some((first_x, first_y))) => (x - first_x, y - first_y),
+                    //Fully transparent frames have nothing to align
+                    _ => (0.0, 0.0),
+                })
+                .collect()
+        }
+        Stabilization::PhaseCorrelation => {
+            let first_mat = luma_mat(first)?;
+            let mut offsets = vec![];
+            for frame in frames {
+                let mut response = 0.0;
+                let shift = imgproc::phase_correlate(
+                    &first_mat,
+                    &luma_mat(frame)?,
+                    &Mat::default()?,
+                    &mut response,
+                )?;
+                offsets.push((shift.x, shift.y));
+            }
+            offsets
+        }
+    };
+
+    Ok(offsets
+        .into_iter()
+        .map(|(x, y)| (x.round() as i32, y.round() as i32))
+        .collect())
+}
+
+//Moves the contents of frame by offset. Pixels that come from outside the frame are handled by edge_mode.
+pub fn shift_frame(frame: &Frame, offset: (i32, i32), edge_mode: EdgeMode) -> Frame {
+    if offset == (0, 0) {
+        return frame.clone();
+    }
+
+    let (w, h) = (frame.width() as i32, frame.height() as i32);
+    Frame(RgbaImage::from_fn(w as u32, h as u32, |x, y| {
+        match (
+            edge_mode.apply(x as i32 - offset.0, w),
+            edge_mode.apply(y as i32 - offset.1, h),
+        ) {
+            (Some(x), Some(y)) => frame[(x as u32, y as u32)],
+            _ => Rgba([0, 0, 0, 0]),
+        }
+    }))
+}
+
+//Turns a flow between two stabilized frames into one between the original frames,
+//which were offset_a and offset_b away from their stabilized positions
+pub fn unstabilize_flow(
+    flow: &FlowField,
+    offset_a: (i32, i32),
+    offset_b: (i32, i32),
+    edge_mode: EdgeMode,
+) -> FlowField {
+    let jitter = (offset_b.0 - offset_a.0, offset_b.1 - offset_a.1);
+    shift_flow(flow, offset_a, jitter, edge_mode)
+}
+
+//The inverse of unstabilize_flow, for flows that were made for the original frames
+pub fn stabilize_flow(
+    flow: &FlowField,
+    offset_a: (i32, i32),
+    offset_b: (i32, i32),
+    edge_mode: EdgeMode,
+) -> FlowField {
+    let jitter = (offset_a.0 - offset_b.0, offset_a.1 - offset_b.1);
+    shift_flow(flow, (-offset_a.0, -offset_a.1), jitter, edge_mode)
+}
+
+//Moves the flow field by offset like shift_frame, and adds jitter to every vector
+fn shift_flow(
+    flow: &FlowField,
+    offset: (i32, i32),
+    jitter: (i32, i32),
+    edge_mode: EdgeMode,
+) -> FlowField {
+    let (w, h) = (flow.width as i32, flow.height as i32);
+
+    let mut data = Vec::with_capacity(flow.data.len());
+    for y in 0..h {
+        for x in 0..w {
+            //Pixels shifted in from outside the frame don't move on their own
+            let [dx, dy] = match (
+                edge_mode.apply(x - offset.0, w),
+                edge_mode.apply(y - offset.1, h),
+            ) {
+                (Some(x), Some(y)) => flow.get(x as usize, y as usize),
+                _ => [0.0, 0.0],
+            };
+            data.push([dx + jitter.0 as f32, dy + jitter.1 as f32]);
+        }
+    }
+
+    FlowField {
+        width: flow.width,
+        height: flow.height,
+        data,
+    }
+}
+
+fn alpha_centroid(frame: &Frame) -> Option<(f64, f64)> {
+    let (mut total, mut sum_x, mut sum_y) = (0.0, 0.0, 0.0);
+    for (x, y, pixel) in frame.enumerate_pixels() {
+        let alpha = pixel[3] as f64;
+        total += alpha;
+        sum_x += x as f64 * alpha;
+        sum_y += y as f64 * alpha;
+    }
+
+    if total == 0.0 {
+        None
+    } else {
+        Some((sum_x / total, sum_y / total))
+    }
+}
+
+//The lowest row that contains an opaque pixel
+fn baseline(frame: &Frame) -> Option<f64> {
+    (0..frame.height())
+        .rev()
+        .find(|y| (0..frame.width()).any(|x| frame[(x, *y)][3] >= BASELINE_ALPHA_THRESHOLD))
+        .map(|y| y as f64)
+}
+
+//Alpha-premultiplied luma as a single channel float Mat, which is what phase correlation expects
+fn luma_mat(frame: &Frame) -> BitmapflowResult<Mat> {
+    let mut mat =
+        unsafe { Mat::new_rows_cols(frame.height() as i32, frame.width() as i32, CV_32FC1)? };
+
+    for (x, y, pixel) in frame.enumerate_pixels() {
+        let [r, g, b, a] = pixel.0;
+        *mat.at_2d_mut::<f32>(y as i32, x as i32)? =
+            (0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32) * a as f32 / 255.0;
+    }
+
+    Ok(mat)
+}
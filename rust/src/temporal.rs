@@ -0,0 +1,147 @@
+use crate::flow::FlowField;
+
+//Every frame pair's flow is estimated on its own, so neighbouring pairs can pick different solutions
+//for the same part of the sprite, which makes the inbetweens flicker. This looks at the pairs around it.
+//NOTE: when the animation doesn't loop, the last flow belongs to the last frame paired with itself,
+//so it's no real motion and is left out.
+
+//The flow of the pair before k, sampled where every pixel of frame k came from.
+//Pixels for which that's unknown are NaN.
+pub fn incoming_motion(flows: &[FlowField], k: usize, looping: bool) -> Option<FlowField> {
+    //The last frame is held, it shouldn't curve
+    if !looping && k + 1 == flows.len() {
+        return None;
+    }
+    let previous = &flows[previous_index(flows.len(), k, looping)?];
+
+    let data = (0..previous.height)
+        .flat_map(|y| (0..previous.width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            //Assumes the flow doesn't change much over its own length
+            let [dx, dy] = previous.get(x, y);
+            sample(previous, x as f32 - dx, y as f32 - dy).unwrap_or([f32::NAN, f32::NAN])
+        })
+        .collect();
+
+    Some(FlowField {
+        width: previous.width,
+        height: previous.height,
+        data,
+    })
+}
+
+//Blends every flow with the flows of the neighbouring pairs, followed along the motion.
+//strength 0 leaves the flows alone, 1 replaces them by the average.
+pub fn smooth_flows_temporally(
+    flows: &[FlowField],
+    strength: f32,
+    looping: bool,
+) -> Vec<FlowField> {
+    let n = flows.len();
+
+    (0..n)
+        .map(|k| {
+            let flow = &flows[k];
+            if !looping && k + 1 == n {
+                return flow.clone();
+            }
+
+            let incoming = incoming_motion(flows, k, looping);
+            let next = next_index(n, k, looping).map(|next| &flows[next]);
+
+            let data = (0..flow.height)
+                .flat_map(|y| (0..flow.width).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    let own = flow.get(x, y);
+                    if !own[0].is_finite() || !own[1].is_finite() {
+                        return own;
+                    }
+
+                    let mut neighbours = vec![own];
+                    if let Some(incoming) = &incoming {
+                        neighbours.push(incoming.get(x, y));
+                    }
+                    if let Some(next) = next {
+                        //Where this pixel ends up in the next frame
+                        if let Some(vector) = sample(next, x as f32 + own[0], y as f32 + own[1]) {
+                            neighbours.push(vector);
+                        }
+                    }
+                    neighbours.retain(|[dx, dy]| dx.is_finite() && dy.is_finite());
+
+                    let count = neighbours.len() as f32;
+                    let mean = neighbours
+                        .iter()
+                        .fold([0.0, 0.0], |[sx, sy], [dx, dy]| [sx + dx, sy + dy]);
+                    [
+                        own[0] * (1.0 - strength) + mean[0] / count * strength,
+                        own[1] * (1.0 - strength) + mean[1] / count * strength,
+                    ]
+                })
+                .collect();
+
+            FlowField {
+                width: flow.width,
+                height: flow.height,
+                data,
+            }
+        })
+        .collect()
+}
+
+//Displacement at time t (0 to 1) along the parabola through where a pixel was in the previous frame,
+//where it is now and where it will be, instead of along a straight line.
+//Falls back to a straight line where the incoming motion is unknown.
+pub fn quadratic_displacement(flow: &FlowField, incoming: &FlowField, t: f32) -> FlowField {
+    let data = flow
+        .data
+        .iter()
+        .zip(&incoming.data)
+        .map(|(&[fx, fy], &[ix, iy])| {
+            if ix.is_finite() && iy.is_finite() {
+                //x(t) = t * (f + i) / 2 + t² * (f - i) / 2, which passes through -i at t=-1 and f at t=1
+                [
+                    t * (fx + ix) / 2.0 + t * t * (fx - ix) / 2.0,
+                    t * (fy + iy) / 2.0 + t * t * (fy - iy) / 2.0,
+                ]
+            } else {
+                [t * fx, t * fy]
+            }
+        })
+        .collect();
+
+    FlowField {
+        width: flow.width,
+        height: flow.height,
+        data,
+    }
+}
+
+fn previous_index(n: usize, k: usize, looping: bool) -> Option<usize> {
+    if k > 0 {
+        Some(k - 1)
+    } else if looping && n > 1 {
+        Some(n - 1)
+    } else {
+        None
+    }
+}
+
+fn next_index(n: usize, k: usize, looping: bool) -> Option<usize> {
+    if looping {
+        Some((k + 1) % n).filter(|next| *next != k)
+    } else {
+        //The last flow isn't real motion, see above
+        Some(k + 1).filter(|next| next + 1 < n)
+    }
+}
+
+//Nearest neighbour lookup, None outside the field or for non-finite vectors
+fn sample(field: &FlowField, x: f32, y: f32) -> Option<[f32; 2]> {
+    let (x, y) = (x.round(), y.round());
+    if !(x >= 0.0 && y >= 0.0 && (x as usize) < field.width && (y as usize) < field.height) {
+        return None;
+    }
+
+    Some(field.get(x as usize, y as usize)).filter(|[dx, dy]| dx.is_finite() && dy.is_finite())
+}
@@ -1,19 +1,35 @@
 use gdnative::{
     api::Node,
-    prelude::{user_data::LocalCellError, *},
+    prelude::{
+        user_data::{LocalCellData, LocalCellError},
+        *,
+    },
 };
 
-use crate::image_holder::ImageHolder;
+use crate::{image_holder::ImageHolder, image_processor::ImageProcessor};
+
+pub fn do_with_singleton<C, T, F>(name: &str, f: F) -> Result<T, LocalCellError>
+where
+    C: NativeClass<Base = Node, UserData = LocalCellData<C>>,
+    F: FnOnce(&C, TRef<'_, Node>) -> T,
+{
+    let singleton = unsafe { autoload::<Node>(name) }
+        .unwrap()
+        .cast_instance::<C>()
+        .unwrap();
+    singleton.map(f)
+}
 
-//NOTE you can generalize this to any singleton (do_with_singleton<ImageHolder>("ImageHolder", ...))
 pub fn do_with_image_holder<T, F: FnOnce(&ImageHolder, TRef<'_, Node>) -> T>(
     f: F,
 ) -> Result<T, LocalCellError> {
-    let imageholder = unsafe { autoload::<Node>("ImageHolder") }
-        .unwrap()
-        .cast_instance::<ImageHolder>()
-        .unwrap();
-    imageholder.map(f)
+    do_with_singleton("ImageHolder", f)
+}
+
+pub fn do_with_image_processor<T, F: FnOnce(&ImageProcessor, TRef<'_, Node>) -> T>(
+    f: F,
+) -> Result<T, LocalCellError> {
+    do_with_singleton("ImageProcessor", f)
 }
 
 pub fn array_to_pair<T>(array: &[T]) -> (&T, &T) {